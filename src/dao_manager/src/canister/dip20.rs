@@ -5,8 +5,8 @@ use candid::Principal;
 use ic_cdk::api::call::CallResult;
 use ic_cdk::export::candid::{self, CandidType, Deserialize};
 
-#[derive(CandidType, Deserialize)]
-enum TxError {
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TxError {
     InsufficientAllowance,
     InsufficientBalance,
     ErrorOperationStyle,
@@ -19,30 +19,30 @@ enum TxError {
 }
 
 #[derive(CandidType, Deserialize)]
-enum Result {
+pub enum Result {
     Ok(candid::Nat),
     Err(TxError),
 }
 
 #[derive(CandidType, Deserialize)]
-struct Metadata {
-    fee: candid::Nat,
-    decimals: u8,
-    owner: candid::Principal,
-    logo: String,
-    name: String,
-    totalSupply: candid::Nat,
-    symbol: String,
+pub struct Metadata {
+    pub fee: candid::Nat,
+    pub decimals: u8,
+    pub owner: candid::Principal,
+    pub logo: String,
+    pub name: String,
+    pub totalSupply: candid::Nat,
+    pub symbol: String,
 }
 
 #[derive(CandidType, Deserialize)]
-struct TokenInfo {
-    holderNumber: u64,
-    deployTime: u64,
-    metadata: Metadata,
-    historySize: u64,
-    cycles: u64,
-    feeTo: candid::Principal,
+pub struct TokenInfo {
+    pub holderNumber: u64,
+    pub deployTime: u64,
+    pub metadata: Metadata,
+    pub historySize: u64,
+    pub cycles: u64,
+    pub feeTo: candid::Principal,
 }
 
 pub struct SERVICE(candid::Principal);
@@ -0,0 +1,112 @@
+// This is an experimental feature to generate Rust binding from Candid.
+// You may want to manually adjust some of the types.
+
+use candid::Principal;
+use ic_cdk::api::call::CallResult;
+use ic_cdk::export::candid::{self, CandidType, Deserialize};
+use serde::Serialize;
+
+#[derive(CandidType, Deserialize, Serialize, Clone, PartialEq, Debug)]
+pub struct Account {
+    pub owner: candid::Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferFromError {
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+    InsufficientAllowance {
+        allowance: candid::Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: candid::Nat,
+        message: String,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Vec<u8>>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Vec<u8>>,
+    pub to: Account,
+    pub amount: candid::Nat,
+    pub fee: Option<candid::Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransferError {
+    BadFee {
+        expected_fee: candid::Nat,
+    },
+    BadBurn {
+        min_burn_amount: candid::Nat,
+    },
+    InsufficientFunds {
+        balance: candid::Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    Duplicate {
+        duplicate_of: candid::Nat,
+    },
+    TemporarilyUnavailable,
+    GenericError {
+        error_code: candid::Nat,
+        message: String,
+    },
+}
+
+pub struct SERVICE(candid::Principal);
+impl SERVICE {
+    pub fn new(principal: Principal) -> Self {
+        SERVICE(principal)
+    }
+    pub async fn icrc1_balance_of(&self, arg0: Account) -> CallResult<(candid::Nat,)> {
+        ic_cdk::call(self.0, "icrc1_balance_of", (arg0,)).await
+    }
+    pub async fn icrc1_fee(&self) -> CallResult<(candid::Nat,)> {
+        ic_cdk::call(self.0, "icrc1_fee", ()).await
+    }
+    pub async fn icrc1_transfer(
+        &self,
+        arg0: TransferArg,
+    ) -> CallResult<(Result<candid::Nat, TransferError>,)> {
+        ic_cdk::call(self.0, "icrc1_transfer", (arg0,)).await
+    }
+    pub async fn icrc2_transfer_from(
+        &self,
+        arg0: TransferFromArgs,
+    ) -> CallResult<(Result<candid::Nat, TransferFromError>,)> {
+        ic_cdk::call(self.0, "icrc2_transfer_from", (arg0,)).await
+    }
+}
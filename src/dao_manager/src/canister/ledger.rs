@@ -1,38 +1,122 @@
 use candid::{CandidType, Deserialize, Principal};
-use dfn_core::api::call_with_cleanup;
-use dfn_protobuf::{protobuf, ProtoBuf};
+use dfn_protobuf::ProtoBuf;
 
-use on_wire::FromWire;
+use on_wire::{FromWire, IntoWire};
 
-use ic_ledger_types::AccountIdentifier;
-use ic_nns_constants::LEDGER_CANISTER_ID;
+use ic_ledger_types::{AccountIdentifier, Subaccount};
 
 use ledger_canister::{Block, BlockArg, BlockRes, Memo, Operation};
 use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
 
-use crate::tool::subnet_raw_rand;
+use crate::error::{ManagerError, ManagerResult};
+use crate::payment::PaymentMethod;
+use crate::plan::CreationPlan;
+use crate::types::MAX_PAGE_SIZE;
+use crate::Data;
+use ic_kit::ic;
 
 #[derive(Serialize, CandidType, Deserialize, Default, Clone, Debug)]
 pub struct ICPService {
     pub transactions: Vec<TransactionItem>,
+    // every block height accepted as a payment, so one transfer never pays twice
+    #[serde(default)]
+    pub consumed_blocks: BTreeSet<u64>,
+    #[serde(default)]
+    last_expiry_sweep: u64,
+    // memos of token payments being pulled, so an approval is never collected twice
+    #[serde(skip)]
+    collecting: BTreeSet<u64>,
+    #[serde(default)]
+    pub ledger: LedgerConfig,
 }
 
+/// ICP ledger payments are made on and the manager account they are collected in.
+#[derive(Serialize, CandidType, Deserialize, Clone, Debug)]
+pub struct LedgerConfig {
+    pub ledger_canister_id: Principal, // the NNS ledger on mainnet, a local ledger on a replica
+    pub receiving_subaccount: Subaccount, // manager subaccount paid intents are swept into
+}
+
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            ledger_canister_id: ic_ledger_types::MAINNET_LEDGER_CANISTER_ID,
+            receiving_subaccount: ic_ledger_types::DEFAULT_SUBACCOUNT,
+        }
+    }
+}
+
+impl LedgerConfig {
+    pub fn receiving_account(&self) -> AccountIdentifier {
+        AccountIdentifier::new(&ic_cdk::api::id(), &self.receiving_subaccount)
+    }
+}
+
+/// How long a payment intent stays open, 24 hours in nanoseconds.
+const INTENT_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// Open intents a single caller may hold at once.
+const MAX_OPEN_INTENTS: usize = 5;
+/// Minimum time between two expiry sweeps, one minute in nanoseconds.
+const EXPIRY_SWEEP_INTERVAL: u64 = 60 * 1_000_000_000;
+/// How long expired, unpaid intents are kept for late payments, 7 days in
+/// nanoseconds. They are removed afterwards.
+const PRUNE_AFTER: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
 impl ICPService {
-    pub async fn get_pay_info(&mut self) -> Result<TransactionItem, String> {
-        let to = AccountIdentifier::new(&ic_cdk::api::id(), &ic_ledger_types::DEFAULT_SUBACCOUNT)
-            .to_string();
+    pub async fn get_pay_info(
+        &mut self,
+        plan_id: u64,
+        method: PaymentMethod,
+    ) -> ManagerResult<TransactionItem> {
+        let plan = ic::get::<Data>().plans.get_plan(plan_id)?;
+        if !plan.enabled {
+            return Err(ManagerError::InvalidPlan(format!(
+                "plan {} is disabled",
+                plan_id
+            )));
+        }
+        let amount = ic::get::<Data>().payments.price(method, &plan)?;
         let caller = ic_cdk::caller();
         let from =
             AccountIdentifier::new(&caller, &ic_ledger_types::DEFAULT_SUBACCOUNT).to_string();
+        if self.open_intents(&from).count() >= MAX_OPEN_INTENTS {
+            return Err(ManagerError::TooManyOpenIntents);
+        }
 
-        let memo = subnet_raw_rand().await?;
-        let amount = 10_000_000_u64;
+        let random = &mut ic::get_mut::<Data>().random;
+        let memo = random.next_u64().await?;
+        let nonce = random.bytes().await?;
+        let subaccount = intent_subaccount(&caller, self.transactions.len() as u64, nonce);
+        let (to, subaccount) = match method {
+            PaymentMethod::Icp => (
+                AccountIdentifier::new(&ic_cdk::api::id(), &subaccount).to_string(),
+                Some(subaccount),
+            ),
+            // ICRC accounts are the manager principal plus an optional subaccount
+            PaymentMethod::Icrc1(_) => (ic_cdk::api::id().to_text(), Some(subaccount)),
+            PaymentMethod::Icrc2(_) | PaymentMethod::Dip20(_) => {
+                (ic_cdk::api::id().to_text(), None)
+            }
+        };
+        let now = ic_cdk::api::time();
         let item = TransactionItem {
             from,
             to,
             memo,
             amount,
             status: 0,
+            plan_id,
+            payer: Some(caller),
+            refund_block_height: None,
+            subaccount,
+            method,
+            ledger_block: None,
+            block_height: None,
+            created_at: now,
+            expires_at: now + INTENT_TTL,
+            plan: Some(plan),
         };
         self.transactions.push(item.clone());
         Ok(item)
@@ -43,33 +127,257 @@ impl ICPService {
         block_height: u64,
         memo: u64,
         status: Option<u8>,
-    ) -> Result<bool, String> {
+    ) -> ManagerResult<TransactionItem> {
         let from =
             AccountIdentifier::new(&caller, &ic_ledger_types::DEFAULT_SUBACCOUNT).to_string();
-        let to = AccountIdentifier::new(&ic_cdk::api::id(), &ic_ledger_types::DEFAULT_SUBACCOUNT)
-            .to_string();
+        let ledger = self.ledger.clone();
+        let index = self
+            .transactions
+            .iter()
+            .position(|transaction| {
+                transaction.from == from
+                    && memo == transaction.memo
+                    && (transaction.status == 0 || (status.is_none() && transaction.late_payable()))
+            })
+            .ok_or(ManagerError::PaymentNotFound)?;
+        // copied out, pruning may move the entry while we await
+        let transaction = self.transactions[index].clone();
+        if status.is_some() {
+            self.transactions[index].status = status.unwrap_or(1); // after crated dao, set to 1
+            return Ok(self.transactions[index].clone());
+        }
+        if transaction.method != PaymentMethod::Icp || transaction.subaccount.is_some() {
+            // token payments and intent subaccounts don't depend on the caller's block
+            if transaction.block_height.is_some() {
+                return Ok(transaction);
+            }
+            if !self.collecting.insert(memo) {
+                return Err(ManagerError::CreationInProgress);
+            }
+            let result = match (transaction.method, transaction.subaccount) {
+                (PaymentMethod::Icp, Some(subaccount)) => {
+                    sweep_subaccount(&ledger, subaccount, memo, transaction.amount).await
+                }
+                (method, subaccount) => {
+                    method
+                        .collect(caller, subaccount, transaction.amount, memo)
+                        .await
+                }
+            };
+            self.collecting.remove(&memo);
+            let block = result?;
+            if transaction.method == PaymentMethod::Icp {
+                self.consumed_blocks.insert(block);
+            }
+            let transaction = self.intent_mut(&from, memo)?;
+            // a late payment revives its expired intent
+            transaction.status = 0;
+            if transaction.method == PaymentMethod::Icp {
+                // paid into the intent's own subaccount, so `block_height` is the sweep's
+                transaction.block_height = Some(block);
+            } else {
+                // token payments are keyed by their memo, their blocks live on another ledger
+                transaction.ledger_block = Some(block);
+                transaction.block_height = Some(memo);
+            }
+            return Ok(transaction.clone());
+        }
+        match transaction.block_height {
+            // verified by an earlier attempt
+            Some(verified) if verified == block_height => return Ok(transaction),
+            Some(verified) => {
+                return Err(ManagerError::PaymentMismatch(format!(
+                    "payment was made with block {}",
+                    verified
+                )))
+            }
+            None => {}
+        }
+        if self.consumed_blocks.contains(&block_height) {
+            return Err(ManagerError::PaymentAlreadyUsed);
+        }
+        check_transfer(
+            ledger.ledger_canister_id,
+            from.clone(),
+            transaction.to,
+            block_height,
+            memo,
+            transaction.amount,
+        )
+        .await?;
+        // another call may have consumed the block while we awaited
+        if !self.consumed_blocks.insert(block_height) {
+            return Err(ManagerError::PaymentAlreadyUsed);
+        }
+        let transaction = self.intent_mut(&from, memo)?;
+        transaction.block_height = Some(block_height);
+        Ok(transaction.clone())
+    }
+    fn intent_mut(&mut self, from: &str, memo: u64) -> ManagerResult<&mut TransactionItem> {
+        self.transactions
+            .iter_mut()
+            .find(|transaction| transaction.from == from && transaction.memo == memo)
+            .ok_or(ManagerError::PaymentNotFound)
+    }
+    /// A page of all intents, oldest first.
+    pub fn transaction_log(&self, offset: u64, limit: u64) -> Vec<TransactionItem> {
+        self.transactions
+            .iter()
+            .skip(offset as usize)
+            .take(limit.min(MAX_PAGE_SIZE) as usize)
+            .cloned()
+            .collect()
+    }
+    /// Unpaid, unexpired intents created by `caller`.
+    pub fn my_intents(&self, caller: Principal) -> Vec<TransactionItem> {
+        let from =
+            AccountIdentifier::new(&caller, &ic_ledger_types::DEFAULT_SUBACCOUNT).to_string();
+        self.open_intents(&from).cloned().collect()
+    }
+    fn open_intents<'a>(&'a self, from: &'a str) -> impl Iterator<Item = &'a TransactionItem> {
+        let now = ic_cdk::api::time();
+        self.transactions.iter().filter(move |transaction| {
+            transaction.from == from
+                && transaction.status == 0
+                && (transaction.expires_at == 0 || transaction.expires_at > now)
+        })
+    }
+    /// Payer of the verified payment made with `block_height` that no dao was
+    /// created for yet.
+    pub fn refundable_payer(&self, block_height: u64) -> ManagerResult<Option<Principal>> {
+        self.transactions
+            .iter()
+            .find(|transaction| {
+                transaction.status == 0 && transaction.block_height == Some(block_height)
+            })
+            .map(|transaction| transaction.payer)
+            .ok_or(ManagerError::PaymentNotFound)
+    }
+    /// Send a verified payment back to `payer`, minus the ledger fee, returning
+    /// the block height of the refund.
+    pub async fn refund(&mut self, block_height: u64, payer: Principal) -> ManagerResult<u64> {
+        let index = self
+            .transactions
+            .iter()
+            .position(|transaction| {
+                transaction.status == 0 && transaction.block_height == Some(block_height)
+            })
+            .ok_or(ManagerError::PaymentNotFound)?;
+        let transaction = &mut self.transactions[index];
+        if transaction.method != PaymentMethod::Icp {
+            return Err(ManagerError::NotRefundable(
+                "only ICP payments can be refunded".to_owned(),
+            ));
+        }
+        let fee = ic_ledger_types::DEFAULT_FEE;
+        if transaction.amount <= fee.e8s() {
+            return Err(ManagerError::NotRefundable(
+                "payment does not cover the transfer fee".to_owned(),
+            ));
+        }
+        // marked before awaiting so a payment is never refunded twice
+        transaction.status = 3;
+        let args = ic_ledger_types::TransferArgs {
+            memo: ic_ledger_types::Memo(transaction.memo),
+            amount: ic_ledger_types::Tokens::from_e8s(transaction.amount - fee.e8s()),
+            fee,
+            from_subaccount: Some(self.ledger.receiving_subaccount),
+            to: AccountIdentifier::new(&payer, &ic_ledger_types::DEFAULT_SUBACCOUNT),
+            created_at_time: None,
+        };
+        let memo = transaction.memo;
+        let result = ic_ledger_types::transfer(self.ledger.ledger_canister_id, args)
+            .await
+            .map_err(ManagerError::rejected_by(self.ledger.ledger_canister_id))
+            .and_then(|res| res.map_err(|e| ManagerError::LedgerError(e.to_string())));
 
+        // refunded intents are never pruned, but earlier ones may have been
+        let transaction = self
+            .transactions
+            .iter_mut()
+            .find(|transaction| {
+                transaction.memo == memo && transaction.block_height == Some(block_height)
+            })
+            .ok_or(ManagerError::PaymentNotFound)?;
+        match result {
+            Ok(refund_block_height) => {
+                transaction.refund_block_height = Some(refund_block_height);
+                Ok(refund_block_height)
+            }
+            Err(err) => {
+                transaction.status = 0;
+                Err(err)
+            }
+        }
+    }
+    /// E8s of verified ICP payments that still owe a dao or a refund.
+    pub fn reserved(&self) -> u64 {
+        self.transactions
+            .iter()
+            .filter(|transaction| {
+                transaction.status == 0
+                    && transaction.block_height.is_some()
+                    && transaction.method == PaymentMethod::Icp
+            })
+            .map(|transaction| transaction.amount)
+            .sum()
+    }
+    /// Units of `token` paid for daos that were not created yet.
+    pub fn reserved_token(&self, token: Principal) -> u64 {
+        self.transactions
+            .iter()
+            .filter(|transaction| {
+                transaction.status == 0
+                    && transaction.block_height.is_some()
+                    && match transaction.method {
+                        PaymentMethod::Icp => false,
+                        PaymentMethod::Icrc1(ledger)
+                        | PaymentMethod::Icrc2(ledger)
+                        | PaymentMethod::Dip20(ledger) => ledger == token,
+                    }
+            })
+            .map(|transaction| transaction.amount)
+            .sum()
+    }
+    /// Replace the ledger, refused while ICP intents are still waiting for
+    /// their payment or their dao, as those are checked against the current one.
+    pub fn set_ledger_config(&mut self, config: LedgerConfig) -> ManagerResult<LedgerConfig> {
+        let in_use = self
+            .transactions
+            .iter()
+            .any(|transaction| transaction.status == 0 && transaction.method == PaymentMethod::Icp);
+        if in_use {
+            return Err(ManagerError::LedgerInUse);
+        }
+        self.ledger = config;
+        Ok(self.ledger.clone())
+    }
+    /// Expire unpaid intents past their deadline, at most once per sweep interval.
+    /// Intents whose payment was already verified are left for `create_dao`,
+    /// and payments arriving late in an intent subaccount can still be claimed
+    /// until the expired intent is pruned.
+    pub fn expire_intents(&mut self) {
+        let now = ic_cdk::api::time();
+        if now < self.last_expiry_sweep + EXPIRY_SWEEP_INTERVAL {
+            return;
+        }
+        self.last_expiry_sweep = now;
         for transaction in &mut self.transactions {
-            if transaction.from == from
-                && memo == transaction.memo
-                && transaction.status == 0
-                && transaction.to == to
+            if transaction.status == 0
+                && transaction.block_height.is_none()
+                && transaction.expires_at != 0
+                && transaction.expires_at <= now
             {
-                if status.is_some() {
-                    transaction.status = status.unwrap_or(1); // after crated dao, set to 1
-                    return Ok(true);
-                } else {
-                    return check_transfer(from, to, block_height, memo, transaction.amount).await;
-                }
+                transaction.status = 2;
             }
         }
-        Err(format!(
-            "Invalid transfer params, from: {} ,to: {}, block_height: {},memo: {}",
-            from, to, block_height, memo
-        ))
-    }
-    pub fn transaction_log(&self) -> Vec<TransactionItem> {
-        self.transactions.clone()
+        let collecting = &self.collecting;
+        self.transactions.retain(|transaction| {
+            transaction.status != 2
+                || transaction.block_height.is_some()
+                || transaction.expires_at + PRUNE_AFTER > now
+                || collecting.contains(&transaction.memo)
+        });
     }
 }
 
@@ -79,36 +387,120 @@ pub struct TransactionItem {
     to: String,
     memo: u64,
     amount: u64,
-    status: u8, // 0 to_pay | 1 paid
+    status: u8, // 0 to_pay | 1 paid | 2 expired | 3 refunded
+    #[serde(default)]
+    pub plan_id: u64, // creation plan the payment is for
+    #[serde(default)]
+    pub block_height: Option<u64>, // ledger block that paid for this item
+    #[serde(default)]
+    pub payer: Option<Principal>, // None for intents created before refunds existed
+    #[serde(default)]
+    pub refund_block_height: Option<u64>, // ledger block of the refund transfer
+    #[serde(default)]
+    pub subaccount: Option<Subaccount>, // manager subaccount paid into, None for memo matched intents
+    #[serde(default)]
+    pub method: PaymentMethod,
+    #[serde(default)]
+    pub ledger_block: Option<u64>, // block on the token ledger for ICRC payments
+    #[serde(default)]
+    pub created_at: u64,
+    #[serde(default)]
+    pub expires_at: u64, // 0 for intents created before expiry existed
+    #[serde(default)]
+    pub plan: Option<CreationPlan>, // the plan as it was paid for, None for older intents
+}
+
+impl TransactionItem {
+    /// Expired intents with their own subaccount can still be paid, the
+    /// funds would otherwise be stranded there.
+    fn late_payable(&self) -> bool {
+        self.status == 2 && self.block_height.is_none() && self.subaccount.is_some()
+    }
+}
+
+/// Manager subaccount an intent is paid into, unique per intent.
+fn intent_subaccount(caller: &Principal, index: u64, nonce: [u8; 32]) -> Subaccount {
+    let mut hasher = Sha256::new();
+    hasher.update(caller.as_slice());
+    hasher.update(index.to_be_bytes());
+    hasher.update(nonce);
+    Subaccount(hasher.finalize().into())
 }
 
-pub async fn get_block(block_height: u64) -> Result<Block, String> {
-    let BlockRes(res) = call_with_cleanup(
-        LEDGER_CANISTER_ID,
-        "block_pb",
-        protobuf,
-        BlockArg(block_height),
+/// Move everything paid into an intent's subaccount to the receiving account,
+/// returning the block height of the sweep.
+async fn sweep_subaccount(
+    ledger: &LedgerConfig,
+    subaccount: Subaccount,
+    memo: u64,
+    price: u64,
+) -> ManagerResult<u64> {
+    let account = AccountIdentifier::new(&ic_cdk::api::id(), &subaccount);
+    let balance = ic_ledger_types::account_balance(
+        ledger.ledger_canister_id,
+        ic_ledger_types::AccountBalanceArgs { account },
     )
     .await
-    .map_err(|e| format!("Failed to fetch block {}", e.1))?;
-    let res = res.ok_or("Block not found")?;
+    .map_err(ManagerError::rejected_by(ledger.ledger_canister_id))?;
+    let fee = ic_ledger_types::DEFAULT_FEE;
+    if balance.e8s() < price || balance.e8s() <= fee.e8s() {
+        return Err(ManagerError::PaymentMismatch(format!(
+            "subaccount holds {} of {} e8s",
+            balance.e8s(),
+            price
+        )));
+    }
+
+    let args = ic_ledger_types::TransferArgs {
+        memo: ic_ledger_types::Memo(memo),
+        amount: ic_ledger_types::Tokens::from_e8s(balance.e8s() - fee.e8s()),
+        fee,
+        from_subaccount: Some(subaccount),
+        to: ledger.receiving_account(),
+        created_at_time: None,
+    };
+    ic_ledger_types::transfer(ledger.ledger_canister_id, args)
+        .await
+        .map_err(ManagerError::rejected_by(ledger.ledger_canister_id))?
+        .map_err(|e| ManagerError::LedgerError(e.to_string()))
+}
+
+pub async fn get_block(ledger_canister_id: Principal, block_height: u64) -> ManagerResult<Block> {
+    let arg = ProtoBuf::new(BlockArg(block_height))
+        .into_bytes()
+        .map_err(ManagerError::LedgerError)?;
+    let bytes = ic_cdk::api::call::call_raw(ledger_canister_id, "block_pb", arg, 0)
+        .await
+        .map_err(ManagerError::rejected_by(ledger_canister_id))?;
+    let BlockRes(res) = ProtoBuf::from_bytes(bytes)
+        .map_err(ManagerError::LedgerError)?
+        .get();
+    let res = res.ok_or_else(|| ManagerError::LedgerError("Block not found".to_owned()))?;
 
     res.map_or_else(
-        |canister_id| Err(format!("canisterId is {:?}", canister_id)),
+        |canister_id| {
+            Err(ManagerError::LedgerError(format!(
+                "canisterId is {:?}",
+                canister_id
+            )))
+        },
         |encoded_block| {
             let bytes = encoded_block.into_vec();
-            Ok(ProtoBuf::from_bytes(bytes)?.get())
+            Ok(ProtoBuf::from_bytes(bytes)
+                .map_err(ManagerError::LedgerError)?
+                .get())
         },
     )
 }
 pub async fn check_transfer(
+    ledger_canister_id: Principal,
     payer: String,
     receiver: String,
     block_height: u64,
     memo: u64,
     price: u64,
-) -> Result<bool, String> {
-    let block = get_block(block_height).await?;
+) -> ManagerResult<bool> {
+    let block = get_block(ledger_canister_id, block_height).await?;
     match block.transaction.operation {
         Operation::Transfer {
             from, to, amount, ..
@@ -120,9 +512,13 @@ pub async fn check_transfer(
             {
                 Ok(true)
             } else {
-                Err("Transaction discipline query failed".to_string())
+                Err(ManagerError::PaymentMismatch(
+                    "Transaction discipline query failed".to_string(),
+                ))
             }
         }
-        _ => Err("Transaction discipline query failed".to_string()),
+        _ => Err(ManagerError::PaymentMismatch(
+            "Transaction is not a transfer".to_string(),
+        )),
     }
 }
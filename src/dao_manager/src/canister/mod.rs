@@ -1,4 +1,5 @@
 pub mod dip20;
 pub mod ext;
+pub mod icrc;
 pub mod ledger;
 pub mod nnsdao_badge;
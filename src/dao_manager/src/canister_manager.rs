@@ -1,4 +1,6 @@
-use candid::Principal;
+use crate::error::ManagerResult;
+use crate::Data;
+use candid::{Nat, Principal};
 use ic_cdk::api::call::RejectionCode;
 use ic_cdk::api::management_canister::main::*;
 use ic_kit::candid::encode_args;
@@ -18,19 +20,25 @@ pub struct InstallCodeArgumentBorrowed<'a> {
 }
 
 pub const WASM: &[u8] = include_bytes!("./dao/nnsdao.wasm.gz");
+pub const WASM_VERSION: &str = "0.1.0";
+
+/// Cycles a new canister starts with, the rest of a plan's cycles are
+/// deposited afterwards, see `creation_deposit`.
+pub const CREATE_CYCLES: u128 = 1_000_000_000_000;
 
 /// Create a default store of 1T cycles
 /// 1T = 1_000_000_000_000
 pub async fn nnsdao_create_canister(
     mut controllers: Vec<Principal>,
-    cycles: u128,
+    compute_allocation: Option<u64>,
+    memory_allocation: Option<u64>,
 ) -> Result<Principal, (RejectionCode, String)> {
     controllers.push(ic::id());
     let canister_id = create_canister(CreateCanisterArgument {
         settings: Some(CanisterSettings {
             controllers: Some(controllers),
-            compute_allocation: None,
-            memory_allocation: None,
+            compute_allocation: compute_allocation.map(Nat::from),
+            memory_allocation: memory_allocation.map(Nat::from),
             freezing_threshold: None,
         }),
     })
@@ -38,15 +46,14 @@ pub async fn nnsdao_create_canister(
     .0
     .canister_id;
 
-    let need_deposit = cycles - 1_000_000_000_000;
-
-    if cycles > 0 && need_deposit > 0 {
-        nnsdao_deposit_cycles(canister_id, need_deposit).await?;
-    }
-
     Ok(canister_id)
 }
 
+/// Cycles to deposit into a new canister so it holds `cycles` in total.
+pub fn creation_deposit(cycles: u128) -> u128 {
+    cycles.saturating_sub(CREATE_CYCLES)
+}
+
 pub async fn nnsdao_change_controller(
     mut controllers: Vec<Principal>,
     canister_id: Principal,
@@ -69,15 +76,16 @@ pub async fn nnsdao_change_controller(
 pub async fn nnsdao_install_code(
     owner: Principal,
     canister_id: Principal,
-) -> Result<(), (RejectionCode, String)> {
+    version: &str,
+) -> ManagerResult<()> {
     let arg = encode_args((owner,)).expect("Failed to serialize the install argument.");
     let install_config = InstallCodeArgumentBorrowed {
         mode: InstallMode::Install,
         canister_id,
-        wasm_module: WASM,
+        wasm_module: ic::get::<Data>().wasm_store.module(version)?,
         arg,
     };
-    ic::call(
+    let _: () = ic::call(
         Principal::management_canister(),
         "install_code",
         (install_config,),
@@ -87,15 +95,21 @@ pub async fn nnsdao_install_code(
     Ok(())
 }
 
+/// Reinstall with `arg`, or with `owner` as the only init argument when omitted.
 pub async fn nnsdao_reinstall_code(
     owner: Principal,
     canister_id: Principal,
-) -> Result<(), (RejectionCode, String)> {
-    let arg = encode_args((owner,)).expect("Failed to serialize the install argument.");
+    version: &str,
+    arg: Option<Vec<u8>>,
+) -> ManagerResult<()> {
+    let arg = match arg {
+        Some(arg) => arg,
+        None => encode_args((owner,)).expect("Failed to serialize the install argument."),
+    };
     let install_config = InstallCodeArgumentBorrowed {
         mode: InstallMode::Reinstall,
         canister_id,
-        wasm_module: WASM,
+        wasm_module: ic::get::<Data>().wasm_store.module(version)?,
         arg,
     };
     let _: () = ic::call(
@@ -108,12 +122,16 @@ pub async fn nnsdao_reinstall_code(
     Ok(())
 }
 
-pub async fn nnsdao_upgrade_code(canister_id: Principal) -> Result<(), (RejectionCode, String)> {
+pub async fn nnsdao_upgrade_code(
+    canister_id: Principal,
+    version: &str,
+    arg: Vec<u8>,
+) -> ManagerResult<()> {
     let install_config = InstallCodeArgumentBorrowed {
         mode: InstallMode::Upgrade,
         canister_id,
-        wasm_module: WASM,
-        arg: vec![],
+        wasm_module: ic::get::<Data>().wasm_store.module(version)?,
+        arg,
     };
     let _: () = ic::call(
         Principal::management_canister(),
@@ -125,6 +143,40 @@ pub async fn nnsdao_upgrade_code(canister_id: Principal) -> Result<(), (Rejectio
     Ok(())
 }
 
+/// Upgrade with pre-flight checks: nothing happens when the canister already
+/// runs the module of `version`, otherwise it is stopped so no messages are in
+/// flight, upgraded, and started again even if the upgrade failed. A failed
+/// restart doesn't change the result, the heartbeat retries it.
+pub async fn nnsdao_checked_upgrade(
+    canister_id: Principal,
+    version: &str,
+    arg: Vec<u8>,
+) -> ManagerResult<()> {
+    let module_hash = ic::get::<Data>().wasm_store.wasm_info(version)?.sha256;
+    let status = nnsdao_canister_status(canister_id).await?;
+    if status.module_hash.as_ref() == Some(&module_hash) {
+        return Ok(());
+    }
+
+    if let Err(err) = stop_canister(CanisterIdRecord { canister_id }).await {
+        // a timed out stop may leave the dao stopping, the heartbeat starts it again
+        ic::get_mut::<Data>().dao_admin.restarts.insert(canister_id);
+        return Err(err.into());
+    }
+    let result = nnsdao_upgrade_code(canister_id, version, arg).await;
+    let started = start_canister(CanisterIdRecord { canister_id }).await;
+    let restarts = &mut ic::get_mut::<Data>().dao_admin.restarts;
+    match started {
+        Ok(()) => restarts.remove(&canister_id),
+        Err(_) => restarts.insert(canister_id),
+    };
+    result
+}
+
+pub async fn nnsdao_start_canister(canister_id: Principal) -> ManagerResult<()> {
+    Ok(start_canister(CanisterIdRecord { canister_id }).await?)
+}
+
 pub async fn nnsdao_canister_status(
     canister_id: Principal,
 ) -> Result<CanisterStatusResponse, (RejectionCode, String)> {
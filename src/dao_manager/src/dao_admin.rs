@@ -1,98 +1,867 @@
 use crate::canister_manager::{
-    nnsdao_canister_status, nnsdao_change_controller, nnsdao_create_canister, nnsdao_install_code,
-    nnsdao_reinstall_code, nnsdao_upgrade_code,
+    creation_deposit, nnsdao_canister_status, nnsdao_change_controller, nnsdao_checked_upgrade,
+    nnsdao_create_canister, nnsdao_deposit_cycles, nnsdao_install_code, nnsdao_reinstall_code,
+    nnsdao_start_canister,
+};
+use crate::error::{ManagerError, ManagerResult};
+use crate::plan::CreationPlan;
+use crate::types::{
+    AddDaoInfo, CanisterIdText, ControllerAction, CreateDaoOptions, CreationStep, Dao, DaoCreation,
+    DaoInfo, DaoListQuery, DaoPage, DaoSortKey, DaoStatusCode, PendingUpgrade, PrincipalText,
+    TagCount, TagMatch, UpgradeBatch, UpgradeKind, UpgradePolicy, UpgradeResult, MAX_PAGE_SIZE,
 };
-use crate::types::{CanisterIdText, ControllerAction, CreateDaoOptions, Dao};
 use crate::Data;
-use candid::{Deserialize, Principal};
+use candid::{encode_args, Deserialize, Principal};
 
 use ic_kit::interfaces::management::CanisterStatusResponse;
 use ic_kit::{ic, RejectionCode};
 use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
 use std::vec;
 
 #[derive(Deserialize, Serialize, Default, Clone)]
 pub struct DaoAdmin {
-    pub dao: Dao,
+    #[serde(default)]
+    pub daos: BTreeMap<Principal, DaoInfo>,
+    // bare canister ids stored before dao records existed, see `migrate_legacy`
+    #[serde(default, rename = "dao", skip_serializing)]
+    legacy_dao: Vec<PrincipalText>,
+    // tag -> daos carrying it, rebuilt from `daos` after upgrade
+    #[serde(skip)]
+    tag_index: BTreeMap<String, BTreeSet<Principal>>,
+    #[serde(default)]
+    pub upgrade_batches: Vec<UpgradeBatch>,
+    #[serde(default)]
+    pub creations: BTreeMap<u64, DaoCreation>,
+    // block heights of creations currently awaiting a call
+    #[serde(skip)]
+    creating: BTreeSet<u64>,
+    // daos left stopped because the restart after an upgrade failed
+    #[serde(default)]
+    pub restarts: BTreeSet<Principal>,
+    #[serde(skip)]
+    restarting: bool,
+    // set while `upgrade_all` or a rollout walks the fleet
+    #[serde(skip)]
+    upgrading: bool,
+}
+
+/// The plan a creation was paid for.
+fn creation_plan(creation: &DaoCreation) -> ManagerResult<CreationPlan> {
+    match &creation.plan {
+        Some(plan) => Ok(plan.clone()),
+        None => ic::get::<Data>().plans.get_plan(creation.plan_id),
+    }
+}
+
+/// Controllers of a canister, only available while the manager is one of them.
+pub async fn dao_controllers(
+    canister_id: Principal,
+) -> Result<Vec<Principal>, (RejectionCode, String)> {
+    let status = nnsdao_canister_status(canister_id).await?;
+    Ok(status.settings.controllers)
 }
-pub fn handle_tuple_err(err: (RejectionCode, String)) -> Result<(), String> {
-    let (code, reason) = err;
-    Err(format!("RejectionCode:{:?}, reason: {:?}", code, reason))
+
+/// Check that a wasm version exists, falling back to the store's default.
+pub fn resolve_version(version: Option<String>) -> ManagerResult<String> {
+    let wasm_store = &ic::get::<Data>().wasm_store;
+    let version = version.unwrap_or_else(|| wasm_store.default_version());
+    wasm_store.wasm_info(&version)?;
+    Ok(version)
+}
+
+pub fn upgrade_result(canister_id: Principal, result: ManagerResult<()>) -> UpgradeResult {
+    let (success, rejection_code, message) = match &result {
+        Ok(()) => (true, None, String::new()),
+        Err(err) => (
+            false,
+            err.rejection_code().map(|code| code as u32),
+            format!("{:?}", err),
+        ),
+    };
+    UpgradeResult {
+        canister_id,
+        success,
+        rejection_code,
+        skipped: matches!(
+            result,
+            Err(ManagerError::UpgradeFrozen) | Err(ManagerError::UpgradePending)
+        ),
+        message,
+        finished_at: ic_cdk::api::time(),
+    }
 }
 
 impl DaoAdmin {
-    pub async fn upgrade_canister(&self, cid: String) -> Result<(), (RejectionCode, String)> {
-        nnsdao_upgrade_code(Principal::from_text(cid).unwrap()).await?;
-        // for canister_id in self.dao.iter() {
-        // }
+    /// Upgrade a dao to `version`, or to the default version when omitted.
+    pub async fn upgrade_canister(
+        &mut self,
+        cid: String,
+        version: Option<String>,
+        arg: Option<Vec<u8>>,
+    ) -> ManagerResult<()> {
+        let canister_id = self.registered_dao(&cid)?;
+        let version = resolve_version(version)?;
+        self.upgrade_dao(canister_id, &version, arg.unwrap_or_default())
+            .await
+    }
+    /// Upgrade every dao that is not archived, carrying on past failures.
+    /// Progress is recorded as it happens, see `upgrade_batches`.
+    pub async fn upgrade_all(
+        &mut self,
+        version: Option<String>,
+        arg: Option<Vec<u8>>,
+    ) -> ManagerResult<UpgradeBatch> {
+        let arg = arg.unwrap_or_default();
+        let version = resolve_version(version)?;
+        self.begin_batch()?;
+        let id = self.upgrade_batches.len() as u64;
+        self.upgrade_batches.push(UpgradeBatch {
+            id,
+            version: version.clone(),
+            started_at: ic_cdk::api::time(),
+            finished_at: None,
+            results: vec![],
+        });
+
+        // ids are collected up front, the registry may change while awaiting
+        let canister_ids: Vec<Principal> = self
+            .daos
+            .values()
+            .filter(|dao| dao.status != DaoStatusCode::Archived)
+            .map(|dao| dao.canister_id)
+            .collect();
+        for canister_id in canister_ids {
+            let result = self.upgrade_dao(canister_id, &version, arg.clone()).await;
+            self.upgrade_batches[id as usize]
+                .results
+                .push(upgrade_result(canister_id, result));
+        }
+
+        self.end_batch();
+        let batch = &mut self.upgrade_batches[id as usize];
+        batch.finished_at = Some(ic_cdk::api::time());
+        Ok(batch.clone())
+    }
+    /// Claim the fleet for a batch of upgrades, so batches and rollout steps
+    /// never stop or upgrade the same dao at once.
+    pub fn begin_batch(&mut self) -> ManagerResult<()> {
+        if self.upgrading {
+            return Err(ManagerError::UpgradeInProgress);
+        }
+        self.upgrading = true;
         Ok(())
     }
-    pub async fn reinstall_canister(&self, cid: String) -> Result<(), (RejectionCode, String)> {
-        let caller = ic_cdk::caller();
-        nnsdao_reinstall_code(caller, Principal::from_text(cid).unwrap()).await?;
-        // for canister_id in self.dao.iter() {
-        // }
+    pub fn end_batch(&mut self) {
+        self.upgrading = false;
+    }
+    pub fn batch_running(&self) -> bool {
+        self.upgrading
+    }
+    pub fn upgrade_batches(&self) -> Vec<UpgradeBatch> {
+        self.upgrade_batches.clone()
+    }
+    pub async fn upgrade_dao(
+        &mut self,
+        canister_id: Principal,
+        version: &str,
+        arg: Vec<u8>,
+    ) -> ManagerResult<()> {
+        let upgrade = PendingUpgrade {
+            version: version.to_owned(),
+            arg,
+            requested_at: ic_cdk::api::time(),
+            kind: UpgradeKind::Upgrade,
+        };
+        self.check_policy(canister_id, &upgrade)?;
+        self.install(canister_id, &upgrade).await
+    }
+    /// Let `upgrade` through on `Auto`, otherwise refuse it or hold it back
+    /// for the dao's controllers.
+    fn check_policy(
+        &mut self,
+        canister_id: Principal,
+        upgrade: &PendingUpgrade,
+    ) -> ManagerResult<()> {
+        let dao = self
+            .daos
+            .get_mut(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?;
+        match dao.upgrade_policy {
+            UpgradePolicy::Auto => Ok(()),
+            UpgradePolicy::Frozen => Err(ManagerError::UpgradeFrozen),
+            UpgradePolicy::NotifyOnly | UpgradePolicy::ManualApproval => {
+                dao.pending_upgrade = Some(upgrade.clone());
+                Err(ManagerError::UpgradePending)
+            }
+        }
+    }
+    /// Install `upgrade` regardless of the policy and record the new version.
+    async fn install(
+        &mut self,
+        canister_id: Principal,
+        upgrade: &PendingUpgrade,
+    ) -> ManagerResult<()> {
+        let arg = upgrade.arg.clone();
+        match upgrade.kind {
+            UpgradeKind::Reinstall => {
+                nnsdao_reinstall_code(ic_cdk::caller(), canister_id, &upgrade.version, Some(arg))
+                    .await?
+            }
+            UpgradeKind::Upgrade | UpgradeKind::Rollback => {
+                nnsdao_checked_upgrade(canister_id, &upgrade.version, arg).await?
+            }
+        }
+        if upgrade.kind != UpgradeKind::Rollback {
+            self.set_wasm_version(canister_id, upgrade.version.clone());
+            return Ok(());
+        }
+        let dao = self
+            .daos
+            .get_mut(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?;
+        if dao.version_history.last() == Some(&upgrade.version) {
+            dao.version_history.pop();
+        }
+        dao.wasm_version = Some(upgrade.version.clone());
         Ok(())
     }
-    pub async fn canister_status(
+    pub async fn set_upgrade_policy(
+        &mut self,
+        canister_id: Principal,
+        policy: UpgradePolicy,
+    ) -> ManagerResult<DaoInfo> {
+        self.authorize_controller(ic_cdk::caller(), canister_id)
+            .await?;
+        let dao = self
+            .daos
+            .get_mut(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?;
+        if policy == UpgradePolicy::Auto {
+            dao.pending_upgrade = None;
+        }
+        dao.upgrade_policy = policy;
+        Ok(dao.clone())
+    }
+    /// Install the upgrade held back by the policy, on behalf of a controller.
+    pub async fn approve_upgrade(&mut self, canister_id: Principal) -> ManagerResult<String> {
+        self.authorize_controller(ic_cdk::caller(), canister_id)
+            .await?;
+        let pending = self
+            .daos
+            .get_mut(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?
+            .pending_upgrade
+            .take()
+            .ok_or(ManagerError::NoPendingUpgrade)?;
+        if let Err(err) = self.install(canister_id, &pending).await {
+            if let Some(dao) = self.daos.get_mut(&canister_id) {
+                dao.pending_upgrade.get_or_insert(pending);
+            }
+            return Err(err);
+        }
+        Ok(pending.version)
+    }
+    /// Upgrade policies belong to the dao, so owners cannot change them.
+    async fn authorize_controller(
         &self,
+        caller: Principal,
         canister_id: Principal,
-    ) -> Result<CanisterStatusResponse, (RejectionCode, String)> {
-        nnsdao_canister_status(canister_id).await
+    ) -> ManagerResult<()> {
+        self.dao_exist(canister_id)?;
+        let controllers = dao_controllers(canister_id).await?;
+        if controllers.contains(&caller) {
+            Ok(())
+        } else {
+            Err(ManagerError::Unauthorized)
+        }
     }
-    fn dao_exist(&self, canister_id: Principal) -> Result<bool, String> {
-        for id in &self.dao {
-            if Principal::from_text(&id).unwrap() == canister_id {
-                return Ok(true);
+    pub async fn reinstall_canister(
+        &mut self,
+        cid: String,
+        version: Option<String>,
+        arg: Option<Vec<u8>>,
+    ) -> ManagerResult<()> {
+        let caller = ic_cdk::caller();
+        let canister_id = self.registered_dao(&cid)?;
+        let version = resolve_version(version)?;
+        let arg = match arg {
+            Some(arg) => arg,
+            None => encode_args((caller,)).expect("Failed to serialize the install argument."),
+        };
+        let reinstall = PendingUpgrade {
+            version,
+            arg,
+            requested_at: ic_cdk::api::time(),
+            kind: UpgradeKind::Reinstall,
+        };
+        self.check_policy(canister_id, &reinstall)?;
+        self.install(canister_id, &reinstall).await
+    }
+    fn set_wasm_version(&mut self, canister_id: Principal, version: String) {
+        if let Some(dao) = self.daos.get_mut(&canister_id) {
+            if let Some(previous) = dao.wasm_version.replace(version.clone()) {
+                // reinstalling the same version leaves nothing to roll back to
+                if previous != version {
+                    dao.version_history.push(previous);
+                }
             }
         }
-        Err("Current DAO does not exist".to_owned())
+    }
+    /// Upgrade a dao back to the version it ran before its last install.
+    pub async fn rollback_dao(&mut self, canister_id: Principal) -> ManagerResult<String> {
+        let previous = self
+            .daos
+            .get(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?
+            .version_history
+            .last()
+            .cloned()
+            .ok_or_else(|| {
+                ManagerError::InvalidWasm("no previous version to roll back to".to_owned())
+            })?;
+        let rollback = PendingUpgrade {
+            version: previous,
+            arg: vec![],
+            requested_at: ic_cdk::api::time(),
+            kind: UpgradeKind::Rollback,
+        };
+        self.check_policy(canister_id, &rollback)?;
+        self.install(canister_id, &rollback).await?;
+        Ok(rollback.version)
+    }
+    /// Parse a canister id and make sure it belongs to the registry, so code
+    /// is never pushed to an unrelated canister the manager controls.
+    fn registered_dao(&self, cid: &str) -> ManagerResult<Principal> {
+        let canister_id = Principal::from_text(cid)
+            .map_err(|err| ManagerError::InvalidPrincipal(err.to_string()))?;
+        self.dao_exist(canister_id)?;
+        Ok(canister_id)
+    }
+    pub async fn canister_status(
+        &self,
+        canister_id: Principal,
+    ) -> ManagerResult<CanisterStatusResponse> {
+        Ok(nnsdao_canister_status(canister_id).await?)
+    }
+    fn dao_exist(&self, canister_id: Principal) -> ManagerResult<bool> {
+        if self.daos.contains_key(&canister_id) {
+            return Ok(true);
+        }
+        Err(ManagerError::DaoNotFound)
     }
     pub fn dao_list(&self) -> Dao {
-        self.dao.clone()
+        self.daos
+            .values()
+            .filter(|dao| dao.status != DaoStatusCode::Archived)
+            .cloned()
+            .collect()
     }
-    pub fn add_dao(&mut self, canister_id: CanisterIdText) -> Dao {
-        self.dao.push(canister_id);
-        self.dao_list()
+    pub fn dao_page(&self, query: DaoListQuery) -> DaoPage {
+        let name = query.name.as_ref().map(|name| name.to_lowercase());
+        let mut daos: Vec<&DaoInfo> = self
+            .daos
+            .values()
+            .filter(|dao| match &query.tag {
+                Some(tag) => dao.info.tags.contains(tag),
+                None => true,
+            })
+            .filter(|dao| match &query.status {
+                Some(status) => &dao.status == status,
+                None => query.include_archived || dao.status != DaoStatusCode::Archived,
+            })
+            .filter(|dao| match &query.creator {
+                Some(creator) => &dao.creator == creator,
+                None => true,
+            })
+            .filter(|dao| match &name {
+                Some(name) => dao.info.name.to_lowercase().contains(name),
+                None => true,
+            })
+            .collect();
+
+        match query.sort {
+            Some(DaoSortKey::CreatedAt) => daos.sort_by_key(|dao| dao.created_at),
+            Some(DaoSortKey::Name) => daos.sort_by_key(|dao| dao.info.name.to_lowercase()),
+            None => {}
+        }
+        if query.descending {
+            daos.reverse();
+        }
+
+        let limit = query.limit.min(MAX_PAGE_SIZE) as usize;
+        DaoPage {
+            total: daos.len() as u64,
+            offset: query.offset,
+            daos: daos
+                .into_iter()
+                .skip(query.offset as usize)
+                .take(limit)
+                .cloned()
+                .collect(),
+        }
     }
-    pub async fn create_dao(&mut self, info: CreateDaoOptions) -> Result<String, String> {
-        // create dao
+    /// Register an existing dao canister. Owners may register any canister,
+    /// everyone else must be a controller of it.
+    pub async fn add_dao(
+        &mut self,
+        canister_id: CanisterIdText,
+        info: AddDaoInfo,
+    ) -> ManagerResult<DaoInfo> {
+        info.validate().map_err(ManagerError::InvalidDaoInfo)?;
         let caller = ic_cdk::caller();
+        let canister_id = Principal::from_text(&canister_id)
+            .map_err(|err| ManagerError::InvalidPrincipal(err.to_string()))?;
+        if self.daos.contains_key(&canister_id) {
+            return Err(ManagerError::DaoAlreadyRegistered);
+        }
 
-        // validate transfer
-        // transer 1ICP
-        ic::get_mut::<Data>()
-            .icp_service
-            .validate_transfer(caller, info.block_height, info.memo, None)
-            .await?;
+        if ic::get::<Data>().owners.is_owner(caller).is_err() {
+            let controllers = dao_controllers(canister_id).await?;
+            if !controllers.contains(&caller) {
+                return Err(ManagerError::Unauthorized);
+            }
+            // the registry may have changed while awaiting
+            if self.daos.contains_key(&canister_id) {
+                return Err(ManagerError::DaoAlreadyRegistered);
+            }
+        }
 
-        // 1T
-        let cycles = 1_000_000_000_000;
+        let dao = DaoInfo {
+            canister_id,
+            creator: caller,
+            created_at: ic_cdk::api::time(),
+            updated_at: 0,
+            info,
+            status: DaoStatusCode::Active,
+            wasm_version: None,
+            version_history: vec![],
+            upgrade_policy: UpgradePolicy::default(),
+            pending_upgrade: None,
+            archived_by: None,
+        };
+        self.insert_dao(dao.clone());
+        Ok(dao)
+    }
+    /// Daos archived by an owner can only be reactivated or deleted by owners.
+    pub async fn set_dao_status(
+        &mut self,
+        canister_id: Principal,
+        status: DaoStatusCode,
+    ) -> ManagerResult<DaoInfo> {
+        let caller = ic_cdk::caller();
+        self.authorize_dao(caller, canister_id).await?;
+        self.authorize_owner_archive(caller, canister_id)?;
+        let dao = self
+            .daos
+            .get_mut(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?;
+        dao.archived_by = match status {
+            DaoStatusCode::Archived => Some(caller),
+            _ => None,
+        };
+        dao.status = status;
+        Ok(dao.clone())
+    }
+    pub async fn delete_dao(&mut self, canister_id: Principal) -> ManagerResult<DaoInfo> {
+        let caller = ic_cdk::caller();
+        self.authorize_dao(caller, canister_id).await?;
+        self.authorize_owner_archive(caller, canister_id)?;
+        self.remove_dao(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)
+    }
+    fn authorize_owner_archive(
+        &self,
+        caller: Principal,
+        canister_id: Principal,
+    ) -> ManagerResult<()> {
+        let owners = &ic::get::<Data>().owners;
+        let archived_by = self
+            .daos
+            .get(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?
+            .archived_by;
+        match archived_by {
+            Some(archiver)
+                if owners.is_owner(archiver).is_ok() && owners.is_owner(caller).is_err() =>
+            {
+                Err(ManagerError::Unauthorized)
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Creators and controllers may edit a dao's metadata without the owners.
+    pub async fn update_dao_info(
+        &mut self,
+        canister_id: Principal,
+        info: AddDaoInfo,
+    ) -> ManagerResult<DaoInfo> {
+        info.validate().map_err(ManagerError::InvalidDaoInfo)?;
+        let caller = ic_cdk::caller();
+        let creator = self
+            .daos
+            .get(&canister_id)
+            .ok_or(ManagerError::DaoNotFound)?
+            .creator;
+        if creator != caller {
+            let controllers = dao_controllers(canister_id).await?;
+            if !controllers.contains(&caller) {
+                return Err(ManagerError::Unauthorized);
+            }
+        }
 
-        let canister_id = nnsdao_create_canister(vec![caller], cycles)
-            .await
-            .map_err(|err| {
-                let (code, reason) = err;
-                format!("RejectionCode:{:?}, reason: {:?}", code, reason)
-            })?;
+        let mut dao = self
+            .daos
+            .get(&canister_id)
+            .cloned()
+            .ok_or(ManagerError::DaoNotFound)?;
+        dao.info = info;
+        dao.updated_at = ic_cdk::api::time();
+        self.insert_dao(dao.clone());
+        Ok(dao)
+    }
+    /// Owners may manage every dao, controllers only their own.
+    async fn authorize_dao(&self, caller: Principal, canister_id: Principal) -> ManagerResult<()> {
+        self.dao_exist(canister_id)?;
+        if ic::get::<Data>().owners.is_owner(caller).is_ok() {
+            return Ok(());
+        }
+        let controllers = dao_controllers(canister_id).await?;
+        if controllers.contains(&caller) {
+            Ok(())
+        } else {
+            Err(ManagerError::Unauthorized)
+        }
+    }
+    pub fn pending_restarts(&self) -> Vec<Principal> {
+        self.restarts.iter().cloned().collect()
+    }
+    /// Start the daos a failed restart left stopped, keeping those that
+    /// still fail for the next heartbeat.
+    pub async fn retry_restarts(&mut self) -> Vec<Principal> {
+        if self.restarting {
+            return self.pending_restarts();
+        }
+        self.restarting = true;
+        for canister_id in self.pending_restarts() {
+            if nnsdao_start_canister(canister_id).await.is_ok() {
+                self.restarts.remove(&canister_id);
+            }
+        }
+        self.restarting = false;
+        self.pending_restarts()
+    }
+    pub fn heartbeat(&mut self) {
+        if !self.restarts.is_empty() && !self.restarting {
+            ic_cdk::spawn(async {
+                ic::get_mut::<Data>().dao_admin.retry_restarts().await;
+            });
+        }
+    }
+    /// Tags with the number of listed daos carrying them. Archived daos are
+    /// left out, like in `dao_by_tags`.
+    pub fn tag_list(&self) -> Vec<TagCount> {
+        self.tag_index
+            .iter()
+            .map(|(tag, daos)| TagCount {
+                tag: tag.clone(),
+                count: daos
+                    .iter()
+                    .filter_map(|id| self.daos.get(id))
+                    .filter(|dao| dao.status != DaoStatusCode::Archived)
+                    .count() as u64,
+            })
+            .filter(|tag| tag.count > 0)
+            .collect()
+    }
+    pub fn dao_by_tags(&self, tags: Vec<String>, mode: TagMatch) -> Dao {
+        let mut sets = tags.iter().map(|tag| self.tag_index.get(tag));
+        let ids: BTreeSet<Principal> = match mode {
+            TagMatch::Any => sets.flatten().flatten().cloned().collect(),
+            TagMatch::All => match sets.next() {
+                Some(Some(first)) => sets.fold(first.clone(), |acc, set| match set {
+                    Some(set) => acc.intersection(set).cloned().collect(),
+                    None => BTreeSet::new(),
+                }),
+                _ => BTreeSet::new(),
+            },
+        };
+        ids.iter()
+            .filter_map(|id| self.daos.get(id))
+            .filter(|dao| dao.status != DaoStatusCode::Archived)
+            .cloned()
+            .collect()
+    }
+    /// Insert or replace a dao record, keeping the tag index in sync.
+    fn insert_dao(&mut self, dao: DaoInfo) {
+        self.remove_dao(&dao.canister_id);
+        for tag in &dao.info.tags {
+            self.tag_index
+                .entry(tag.clone())
+                .or_default()
+                .insert(dao.canister_id);
+        }
+        self.daos.insert(dao.canister_id, dao);
+    }
+    fn remove_dao(&mut self, canister_id: &Principal) -> Option<DaoInfo> {
+        let dao = self.daos.remove(canister_id)?;
+        for tag in &dao.info.tags {
+            if let Some(daos) = self.tag_index.get_mut(tag) {
+                daos.remove(canister_id);
+                if daos.is_empty() {
+                    self.tag_index.remove(tag);
+                }
+            }
+        }
+        Some(dao)
+    }
+    pub fn rebuild_tag_index(&mut self) {
+        self.tag_index.clear();
+        for dao in self.daos.values() {
+            for tag in &dao.info.tags {
+                self.tag_index
+                    .entry(tag.clone())
+                    .or_default()
+                    .insert(dao.canister_id);
+            }
+        }
+    }
+    /// Convert canister ids persisted by older versions into dao records.
+    pub fn migrate_legacy(&mut self) {
+        for id in std::mem::take(&mut self.legacy_dao) {
+            let canister_id = match Principal::from_text(&id) {
+                Ok(canister_id) => canister_id,
+                Err(_) => continue,
+            };
+            self.daos.entry(canister_id).or_insert(DaoInfo {
+                canister_id,
+                creator: Principal::anonymous(),
+                created_at: 0,
+                updated_at: 0,
+                info: AddDaoInfo::default(),
+                status: DaoStatusCode::Active,
+                wasm_version: None,
+                version_history: vec![],
+                upgrade_policy: UpgradePolicy::default(),
+                pending_upgrade: None,
+                archived_by: None,
+            });
+        }
+    }
+    /// Create a dao for a verified payment. Every step is persisted under the
+    /// payment's block height, so calling again after a failure resumes where
+    /// the previous attempt stopped.
+    pub async fn create_dao(&mut self, info: CreateDaoOptions) -> ManagerResult<String> {
+        let caller = ic_cdk::caller();
+        let mut block_height = info.block_height;
+        // subaccount and token payments are keyed by their sweep block or memo,
+        // so a retry with the original options finds them through the memo
+        if !self.creations.contains_key(&block_height) {
+            if let Some(creation) = self
+                .creations
+                .values()
+                .find(|creation| creation.memo == info.memo && creation.creator == caller)
+            {
+                block_height = creation.block_height;
+            }
+        }
 
-        nnsdao_install_code(caller, canister_id)
-            .await
-            .map_err(|err| {
-                let (code, reason) = err;
-                format!("RejectionCode:{:?}, reason: {:?}", code, reason)
-            })?;
+        match self.creations.get(&block_height) {
+            Some(creation) if creation.creator != caller => return Err(ManagerError::Unauthorized),
+            Some(_) => {}
+            None => {
+                // reject bad metadata before the payment is consumed
+                info.info.validate().map_err(ManagerError::InvalidDaoInfo)?;
+                // validate transfer against the price of the plan it was made for
+                let transaction = ic::get_mut::<Data>()
+                    .icp_service
+                    .validate_transfer(caller, block_height, info.memo, None)
+                    .await?;
+                // subaccount payments are keyed by the block that swept them
+                block_height = transaction.block_height.unwrap_or(block_height);
+                // later plan changes don't alter what was paid for
+                let plan = match transaction.plan {
+                    Some(plan) => plan,
+                    None => ic::get::<Data>().plans.get_plan(transaction.plan_id)?,
+                };
+                let wasm_version = resolve_version(plan.wasm_version.clone())?;
+                self.creations.entry(block_height).or_insert(DaoCreation {
+                    block_height,
+                    memo: info.memo,
+                    creator: caller,
+                    plan_id: plan.id,
+                    info: info.info,
+                    step: CreationStep::Paid,
+                    canister_id: None,
+                    wasm_version,
+                    last_error: None,
+                    updated_at: ic_cdk::api::time(),
+                    plan: Some(plan),
+                });
+            }
+        }
+
+        if !self.creating.insert(block_height) {
+            return Err(ManagerError::CreationInProgress);
+        }
+        let result = self.advance_creation(block_height).await;
+        self.creating.remove(&block_height);
+
+        let creation = self
+            .creations
+            .get_mut(&block_height)
+            .ok_or(ManagerError::PaymentNotFound)?;
+        creation.updated_at = ic_cdk::api::time();
+        match result {
+            Ok(canister_id) => {
+                creation.last_error = None;
+                Ok(canister_id.to_text())
+            }
+            Err(err) => {
+                creation.last_error = Some(format!("{:?}", err));
+                Err(err)
+            }
+        }
+    }
+    async fn advance_creation(&mut self, block_height: u64) -> ManagerResult<Principal> {
+        loop {
+            let creation = self
+                .creations
+                .get(&block_height)
+                .cloned()
+                .ok_or(ManagerError::PaymentNotFound)?;
+            let next = match creation.step {
+                CreationStep::Paid => {
+                    let plan = creation_plan(&creation)?;
+                    let canister_id = nnsdao_create_canister(
+                        vec![creation.creator],
+                        plan.compute_allocation,
+                        plan.memory_allocation,
+                    )
+                    .await?;
+                    // recorded before anything else can fail, so a retry never creates another
+                    self.set_creation_canister(block_height, canister_id);
+                    CreationStep::CanisterAllocated
+                }
+                CreationStep::CanisterAllocated => {
+                    let canister_id = creation.canister_id.ok_or(ManagerError::DaoNotFound)?;
+                    let plan = creation_plan(&creation)?;
+                    let deposit = creation_deposit(plan.cycles as u128);
+                    if deposit > 0 {
+                        nnsdao_deposit_cycles(canister_id, deposit).await?;
+                    }
+                    CreationStep::CanisterCreated
+                }
+                CreationStep::CanisterCreated => {
+                    let canister_id = creation.canister_id.ok_or(ManagerError::DaoNotFound)?;
+                    // the install may have landed even though its reply was lost
+                    let module_hash = ic::get::<Data>()
+                        .wasm_store
+                        .wasm_info(&creation.wasm_version)?
+                        .sha256;
+                    let status = nnsdao_canister_status(canister_id).await?;
+                    if status.module_hash != Some(module_hash) {
+                        nnsdao_install_code(creation.creator, canister_id, &creation.wasm_version)
+                            .await?;
+                    }
+                    CreationStep::CodeInstalled
+                }
+                CreationStep::CodeInstalled => {
+                    let canister_id = creation.canister_id.ok_or(ManagerError::DaoNotFound)?;
+                    self.insert_dao(DaoInfo {
+                        canister_id,
+                        creator: creation.creator,
+                        created_at: ic_cdk::api::time(),
+                        updated_at: 0,
+                        info: creation.info,
+                        status: DaoStatusCode::Active,
+                        wasm_version: Some(creation.wasm_version),
+                        version_history: vec![],
+                        upgrade_policy: UpgradePolicy::default(),
+                        pending_upgrade: None,
+                        archived_by: None,
+                    });
+                    // set transaction status 1
+                    ic::get_mut::<Data>()
+                        .icp_service
+                        .validate_transfer(creation.creator, block_height, creation.memo, Some(1))
+                        .await?;
+                    CreationStep::Registered
+                }
+                CreationStep::Registered => {
+                    return creation.canister_id.ok_or(ManagerError::DaoNotFound);
+                }
+                CreationStep::Refunded => {
+                    return Err(ManagerError::NotRefundable(
+                        "payment was already refunded".to_owned(),
+                    ));
+                }
+            };
+            if let Some(creation) = self.creations.get_mut(&block_height) {
+                creation.step = next;
+            }
+        }
+    }
+    /// Return a verified payment no dao was registered for to its payer.
+    pub async fn refund_payment(&mut self, block_height: u64) -> ManagerResult<u64> {
+        let payer = match self.creations.get(&block_height) {
+            Some(creation) => match creation.step {
+                CreationStep::Registered => {
+                    return Err(ManagerError::NotRefundable(
+                        "the dao was already created".to_owned(),
+                    ))
+                }
+                CreationStep::Refunded => {
+                    return Err(ManagerError::NotRefundable(
+                        "payment was already refunded".to_owned(),
+                    ))
+                }
+                // the canister holds the manager's cycles, it would be orphaned
+                _ if creation.canister_id.is_some() => {
+                    return Err(ManagerError::NotRefundable(
+                        "a canister was already created, retry create_dao instead".to_owned(),
+                    ))
+                }
+                _ => creation.creator,
+            },
+            None => ic::get::<Data>()
+                .icp_service
+                .refundable_payer(block_height)?
+                .ok_or_else(|| ManagerError::NotRefundable("payer is unknown".to_owned()))?,
+        };
 
-        let canister_id = canister_id.to_text();
-        self.dao.push(canister_id.clone());
-        // set transaction status 1
-        ic::get_mut::<Data>()
+        // keep create_dao from resuming the creation while the refund is in flight
+        if !self.creating.insert(block_height) {
+            return Err(ManagerError::CreationInProgress);
+        }
+        let result = ic::get_mut::<Data>()
             .icp_service
-            .validate_transfer(caller, info.block_height, info.memo, Some(1))
-            .await?;
-        Ok(canister_id)
+            .refund(block_height, payer)
+            .await;
+        self.creating.remove(&block_height);
+
+        if result.is_ok() {
+            if let Some(creation) = self.creations.get_mut(&block_height) {
+                creation.step = CreationStep::Refunded;
+                creation.last_error = None;
+                creation.updated_at = ic_cdk::api::time();
+            }
+        }
+        result
+    }
+    fn set_creation_canister(&mut self, block_height: u64, canister_id: Principal) {
+        if let Some(creation) = self.creations.get_mut(&block_height) {
+            creation.canister_id = Some(canister_id);
+        }
+    }
+    pub fn dao_creations(&self, creator: Principal) -> Vec<DaoCreation> {
+        self.creations
+            .values()
+            .filter(|creation| creation.creator == creator)
+            .cloned()
+            .collect()
     }
-    pub async fn update_dao_controller(&mut self, action: ControllerAction) -> Result<(), String> {
+    pub async fn update_dao_controller(&mut self, action: ControllerAction) -> ManagerResult<()> {
         let mut owners = ic::get::<Data>().owners.get_owners();
 
         match action {
@@ -104,8 +873,177 @@ impl DaoAdmin {
             }
         };
 
-        nnsdao_change_controller(owners, ic_cdk::id())
-            .await
-            .or_else(handle_tuple_err)
+        Ok(nnsdao_change_controller(owners, ic_cdk::id()).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dao(id: u8, tags: &[&str]) -> DaoInfo {
+        DaoInfo {
+            canister_id: Principal::from_slice(&[id]),
+            creator: Principal::anonymous(),
+            created_at: id as u64,
+            updated_at: id as u64,
+            info: AddDaoInfo {
+                name: format!("dao {}", id),
+                tags: tags.iter().map(|tag| tag.to_string()).collect(),
+                ..Default::default()
+            },
+            status: DaoStatusCode::Active,
+            wasm_version: None,
+            version_history: vec![],
+            upgrade_policy: UpgradePolicy::Auto,
+            pending_upgrade: None,
+            archived_by: None,
+        }
+    }
+
+    #[test]
+    fn migrate_legacy_converts_canister_ids() {
+        let registered = dao(1, &["defi"]);
+        let mut admin = DaoAdmin::default();
+        admin
+            .daos
+            .insert(registered.canister_id, registered.clone());
+        admin.legacy_dao = vec![
+            registered.canister_id.to_text(),
+            Principal::from_slice(&[2]).to_text(),
+            "not a principal".to_owned(),
+        ];
+        admin.migrate_legacy();
+
+        assert!(admin.legacy_dao.is_empty());
+        assert_eq!(admin.daos.len(), 2);
+        // records that already exist are kept as they are
+        assert_eq!(admin.daos[&registered.canister_id].info.name, "dao 1");
+        let migrated = &admin.daos[&Principal::from_slice(&[2])];
+        assert!(migrated.status == DaoStatusCode::Active);
+        assert_eq!(migrated.wasm_version, None);
+    }
+
+    fn admin(count: u8) -> DaoAdmin {
+        let mut admin = DaoAdmin::default();
+        for id in 0..count {
+            admin.insert_dao(dao(id, &[]));
+        }
+        admin
+    }
+
+    fn query(offset: u64, limit: u64) -> DaoListQuery {
+        DaoListQuery {
+            offset,
+            limit,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dao_page_returns_requested_slice() {
+        let page = admin(5).dao_page(query(1, 2));
+        assert_eq!(page.total, 5);
+        assert_eq!(page.offset, 1);
+        assert_eq!(page.daos.len(), 2);
+        assert_eq!(page.daos[0].info.name, "dao 1");
+    }
+
+    #[test]
+    fn dao_page_past_the_end_is_empty() {
+        let page = admin(3).dao_page(query(10, 5));
+        assert_eq!(page.total, 3);
+        assert_eq!(page.offset, 10);
+        assert!(page.daos.is_empty());
+    }
+
+    #[test]
+    fn dao_page_caps_limit() {
+        let page = admin(120).dao_page(query(0, 1_000));
+        assert_eq!(page.daos.len(), MAX_PAGE_SIZE as usize);
+    }
+
+    #[test]
+    fn dao_page_hides_archived() {
+        let mut admin = admin(3);
+        if let Some(dao) = admin.daos.values_mut().next() {
+            dao.status = DaoStatusCode::Archived;
+        }
+        assert_eq!(admin.dao_page(query(0, 10)).total, 2);
+        let mut query = query(0, 10);
+        query.include_archived = true;
+        assert_eq!(admin.dao_page(query).total, 3);
+    }
+
+    fn tagged() -> DaoAdmin {
+        let mut admin = DaoAdmin::default();
+        admin.insert_dao(dao(1, &["defi", "nft"]));
+        admin.insert_dao(dao(2, &["defi"]));
+        admin.insert_dao(dao(3, &["game"]));
+        admin
+    }
+
+    fn ids(daos: Dao) -> Vec<Principal> {
+        daos.iter().map(|dao| dao.canister_id).collect()
+    }
+
+    fn tags(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn dao_by_tags_any_and_all() {
+        let admin = tagged();
+        let (one, two, three) = (
+            Principal::from_slice(&[1]),
+            Principal::from_slice(&[2]),
+            Principal::from_slice(&[3]),
+        );
+        assert_eq!(
+            ids(admin.dao_by_tags(tags(&["nft", "game"]), TagMatch::Any)),
+            vec![one, three]
+        );
+        assert_eq!(
+            ids(admin.dao_by_tags(tags(&["defi", "nft"]), TagMatch::All)),
+            vec![one]
+        );
+        assert_eq!(
+            ids(admin.dao_by_tags(tags(&["defi"]), TagMatch::All)),
+            vec![one, two]
+        );
+        assert!(admin
+            .dao_by_tags(tags(&["defi", "unknown"]), TagMatch::All)
+            .is_empty());
+        assert!(admin.dao_by_tags(vec![], TagMatch::All).is_empty());
+    }
+
+    #[test]
+    fn tag_index_follows_updates_and_removals() {
+        let mut admin = tagged();
+        admin.insert_dao(dao(1, &["game"]));
+        assert!(admin.dao_by_tags(tags(&["nft"]), TagMatch::Any).is_empty());
+        assert_eq!(admin.dao_by_tags(tags(&["game"]), TagMatch::Any).len(), 2);
+        admin.remove_dao(&Principal::from_slice(&[3]));
+        assert!(!admin.tag_index.contains_key("nft"));
+        assert_eq!(admin.tag_index["game"].len(), 1);
+
+        let index = admin.tag_index.clone();
+        admin.rebuild_tag_index();
+        assert_eq!(admin.tag_index, index);
+    }
+
+    #[test]
+    fn archived_daos_are_not_counted_or_listed() {
+        let mut admin = tagged();
+        if let Some(dao) = admin.daos.get_mut(&Principal::from_slice(&[3])) {
+            dao.status = DaoStatusCode::Archived;
+        }
+        assert!(admin.dao_by_tags(tags(&["game"]), TagMatch::Any).is_empty());
+        let counts: Vec<(String, u64)> = admin
+            .tag_list()
+            .into_iter()
+            .map(|tag| (tag.tag, tag.count))
+            .collect();
+        assert_eq!(counts, vec![("defi".to_owned(), 2), ("nft".to_owned(), 1)]);
     }
 }
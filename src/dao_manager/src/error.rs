@@ -0,0 +1,70 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_kit::RejectionCode;
+
+/// Error returned by every public endpoint of the manager.
+#[derive(Deserialize, Clone, CandidType, Debug)]
+pub enum ManagerError {
+    Unauthorized,
+    DaoNotFound,
+    DaoAlreadyRegistered,
+    InvalidPrincipal(String),
+    InvalidDaoInfo(String),
+    PaymentNotFound,
+    PlanNotFound,
+    InvalidPlan(String),
+    PaymentMismatch(String),
+    PaymentAlreadyUsed, // the ledger block already paid for another item
+    TooManyOpenIntents,
+    TokenNotFound,
+    InvalidToken(String),
+    CreationInProgress,
+    NotRefundable(String),
+    InvalidTreasury(String),
+    WithdrawalInProgress,
+    LedgerInUse, // ICP intents are still open on the current ledger
+    LedgerError(String),
+    WasmNotFound,
+    InvalidWasm(String),
+    UpgradeFrozen,
+    UpgradePending,    // waiting for the dao's controllers to approve
+    UpgradeInProgress, // another batch or rollout step is upgrading the fleet
+    NoPendingUpgrade,
+    NoActiveRollout,
+    RolloutNotFound,
+    InvalidRollout(String),
+    ManagementCanisterRejected {
+        code: RejectionCode,
+        msg: String,
+    },
+    CanisterRejected {
+        canister: Principal,
+        code: RejectionCode,
+        msg: String,
+    }, // ledgers and tokens
+}
+
+pub type ManagerResult<T> = Result<T, ManagerError>;
+
+impl ManagerError {
+    pub fn rejection_code(&self) -> Option<RejectionCode> {
+        match self {
+            ManagerError::ManagementCanisterRejected { code, .. }
+            | ManagerError::CanisterRejected { code, .. } => Some(*code),
+            _ => None,
+        }
+    }
+    /// Map a rejected call to `canister`; `From` is for the management canister only.
+    pub fn rejected_by(canister: Principal) -> impl FnOnce((RejectionCode, String)) -> Self {
+        move |(code, msg)| ManagerError::CanisterRejected {
+            canister,
+            code,
+            msg,
+        }
+    }
+}
+
+impl From<(RejectionCode, String)> for ManagerError {
+    fn from((code, msg): (RejectionCode, String)) -> Self {
+        ManagerError::ManagementCanisterRejected { code, msg }
+    }
+}
@@ -1,6 +1,13 @@
+use crate::Data;
 use ic_cdk_macros::heartbeat;
+use ic_kit::ic;
 
 #[heartbeat]
 fn heartbeat() {
-  //  ic_cdk::println!("heat");
-}
\ No newline at end of file
+    let data = ic::get_mut::<Data>();
+    data.icp_service.expire_intents();
+    data.dao_admin.heartbeat();
+    data.random.heartbeat();
+    data.rollout.heartbeat();
+    data.treasury.heartbeat();
+}
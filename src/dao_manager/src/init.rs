@@ -1,11 +1,16 @@
+use crate::canister::ledger::LedgerConfig;
 use crate::Data;
 use ic_cdk_macros::init;
 use ic_kit::ic;
 
 #[init]
-fn init() {
+#[candid::candid_method(init)]
+fn init(ledger: Option<LedgerConfig>) {
     ic_cdk::setup();
 
     let data = ic::get_mut::<Data>();
     data.owners.add_owner(ic_cdk::caller());
+    if let Some(ledger) = ledger {
+        data.icp_service.ledger = ledger;
+    }
 }
@@ -1,33 +1,59 @@
 mod canister;
 mod canister_manager;
 mod dao_admin;
+mod error;
 mod heartbeat;
 mod init;
 mod owner;
+mod payment;
+mod plan;
+mod random;
+mod rollout;
 pub mod tool;
+mod treasury;
 mod types;
+mod wasm_store;
 
 use dao_admin::DaoAdmin;
+use error::ManagerResult;
 use ic_cdk::api::stable::{StableReader, StableWriter};
 use ic_cdk::export::candid::Principal;
 use ic_cdk_macros::*;
+use ic_kit::ic;
 use ic_kit::interfaces::management::CanisterStatusResponse;
-use ic_kit::{ic, RejectionCode};
+use ic_ledger_types::AccountIdentifier;
 use owner::{is_owner, OwnerService};
 use serde::{Deserialize, Serialize};
 use std::io::Read;
-use std::result::Result;
 use std::string::String;
-use types::{ControllerAction, CreateDaoOptions, DaoInfo};
+use types::{
+    AddDaoInfo, ControllerAction, CreateDaoOptions, DaoCreation, DaoInfo, DaoListQuery, DaoPage,
+    DaoStatusCode, TagCount, TagMatch, UpgradeBatch, UpgradePolicy,
+};
 
-use crate::canister::ledger::{ICPService, TransactionItem};
+use crate::canister::icrc::Account;
+use crate::canister::ledger::{ICPService, LedgerConfig, TransactionItem};
+use crate::payment::{Dip20Token, IcrcToken, PaymentMethod, PaymentService};
+use crate::plan::{CreationPlan, PlanService};
+use crate::random::RandomService;
+use crate::rollout::{Rollout, RolloutConfig, RolloutService};
+use crate::treasury::{
+    TokenWithdrawal, TreasuryBalance, TreasuryConfig, TreasuryService, Withdrawal,
+};
 use crate::types::{CanisterIdText, Dao};
+use crate::wasm_store::{WasmInfo, WasmStore};
 
 #[derive(Default)]
 pub struct Data {
     pub owners: OwnerService,
     pub dao_admin: DaoAdmin,
     pub icp_service: ICPService,
+    pub wasm_store: WasmStore,
+    pub rollout: RolloutService,
+    pub plans: PlanService,
+    pub payments: PaymentService,
+    pub treasury: TreasuryService,
+    pub random: RandomService,
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -38,6 +64,16 @@ pub struct DataV0 {
     pub dao_admin: DaoAdmin,
     #[serde(default)]
     pub icp_service: ICPService,
+    #[serde(default)]
+    pub wasm_store: WasmStore,
+    #[serde(default)]
+    pub rollout: RolloutService,
+    #[serde(default)]
+    pub plans: PlanService,
+    #[serde(default)]
+    pub payments: PaymentService,
+    #[serde(default)]
+    pub treasury: TreasuryService,
 }
 
 #[query]
@@ -46,42 +82,257 @@ fn dao_list() -> Dao {
     ic::get::<Data>().dao_admin.dao_list()
 }
 
+#[query]
+#[candid::candid_method(query)]
+fn dao_page(query: DaoListQuery) -> DaoPage {
+    ic::get::<Data>().dao_admin.dao_page(query)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn tag_list() -> Vec<TagCount> {
+    ic::get::<Data>().dao_admin.tag_list()
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn dao_by_tags(tags: Vec<String>, mode: TagMatch) -> Dao {
+    ic::get::<Data>().dao_admin.dao_by_tags(tags, mode)
+}
+
 #[update]
 #[candid::candid_method(update)]
-async fn canister_status() -> Result<CanisterStatusResponse, (RejectionCode, String)> {
+async fn canister_status() -> ManagerResult<CanisterStatusResponse> {
     ic::get::<Data>()
         .dao_admin
         .canister_status(ic_cdk::id())
         .await
 }
 
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn transaction_log(offset: u64, limit: u64) -> Vec<TransactionItem> {
+    ic::get::<Data>().icp_service.transaction_log(offset, limit)
+}
+
 #[query]
 #[candid::candid_method(query)]
-fn transaction_log() -> Vec<TransactionItem> {
-    ic::get::<Data>().icp_service.transaction_log()
+fn my_pay_intents() -> Vec<TransactionItem> {
+    ic::get::<Data>().icp_service.my_intents(ic_cdk::caller())
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn get_pay_info(
+    plan_id: u64,
+    method: Option<PaymentMethod>,
+) -> ManagerResult<TransactionItem> {
+    ic::get_mut::<Data>()
+        .icp_service
+        .get_pay_info(plan_id, method.unwrap_or_default())
+        .await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn ledger_config() -> LedgerConfig {
+    ic::get::<Data>().icp_service.ledger.clone()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn set_ledger_config(config: LedgerConfig) -> ManagerResult<LedgerConfig> {
+    ic::get_mut::<Data>().icp_service.set_ledger_config(config)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn treasury_balance() -> ManagerResult<TreasuryBalance> {
+    ic::get::<Data>().treasury.balance().await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn withdraw_treasury(
+    to: AccountIdentifier,
+    amount: u64,
+    memo: u64,
+) -> ManagerResult<Withdrawal> {
+    ic::get_mut::<Data>()
+        .treasury
+        .withdraw(to, amount, memo, ic_cdk::caller())
+        .await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn split_treasury(memo: u64) -> ManagerResult<Vec<Withdrawal>> {
+    ic::get_mut::<Data>()
+        .treasury
+        .split(memo, ic_cdk::caller())
+        .await
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn treasury_config() -> TreasuryConfig {
+    ic::get::<Data>().treasury.config.clone()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn set_treasury_config(config: TreasuryConfig) -> ManagerResult<TreasuryConfig> {
+    ic::get_mut::<Data>().treasury.set_config(config)
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn treasury_withdrawals() -> Vec<Withdrawal> {
+    ic::get::<Data>().treasury.withdrawals()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn token_balance(token: Principal) -> ManagerResult<u64> {
+    ic::get::<Data>().payments.token_balance(token).await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn withdraw_token(
+    token: Principal,
+    to: Account,
+    amount: u64,
+) -> ManagerResult<TokenWithdrawal> {
+    ic::get_mut::<Data>()
+        .treasury
+        .withdraw_token(token, to, amount, ic_cdk::caller())
+        .await
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn token_withdrawals() -> Vec<TokenWithdrawal> {
+    ic::get::<Data>().treasury.token_withdrawals()
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn icrc_token_list() -> Vec<IcrcToken> {
+    ic::get::<Data>().payments.token_list()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn set_icrc_token(token: IcrcToken) -> ManagerResult<IcrcToken> {
+    ic::get_mut::<Data>().payments.set_token(token)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn dip20_token_list() -> Vec<Dip20Token> {
+    ic::get::<Data>().payments.dip20_token_list()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn set_dip20_token(token: Dip20Token) -> ManagerResult<Dip20Token> {
+    ic::get_mut::<Data>().payments.set_dip20_token(token).await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn plan_list() -> Vec<CreationPlan> {
+    ic::get::<Data>().plans.plan_list()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn set_plan(plan: CreationPlan) -> ManagerResult<CreationPlan> {
+    ic::get_mut::<Data>().plans.set_plan(plan)
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn add_dao(canister_id: CanisterIdText, info: AddDaoInfo) -> ManagerResult<DaoInfo> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .add_dao(canister_id, info)
+        .await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn update_dao_info(canister_id: Principal, info: AddDaoInfo) -> ManagerResult<DaoInfo> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .update_dao_info(canister_id, info)
+        .await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn set_upgrade_policy(
+    canister_id: Principal,
+    policy: UpgradePolicy,
+) -> ManagerResult<DaoInfo> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .set_upgrade_policy(canister_id, policy)
+        .await
 }
 
 #[update]
 #[candid::candid_method(update)]
-async fn get_pay_info() -> Result<TransactionItem, String> {
-    ic::get_mut::<Data>().icp_service.get_pay_info().await
+async fn approve_upgrade(canister_id: Principal) -> ManagerResult<String> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .approve_upgrade(canister_id)
+        .await
+}
+
+#[update]
+#[candid::candid_method(update)]
+async fn set_dao_status(canister_id: Principal, status: DaoStatusCode) -> ManagerResult<DaoInfo> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .set_dao_status(canister_id, status)
+        .await
 }
 
 #[update]
 #[candid::candid_method(update)]
-fn add_dao(canister_id: CanisterIdText) -> Dao {
-    ic::get_mut::<Data>().dao_admin.add_dao(canister_id)
+async fn delete_dao(canister_id: Principal) -> ManagerResult<DaoInfo> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .delete_dao(canister_id)
+        .await
 }
 
 #[update]
 #[candid::candid_method(update)]
-async fn create_dao(info: CreateDaoOptions) -> Result<String, String> {
+async fn create_dao(info: CreateDaoOptions) -> ManagerResult<String> {
     ic::get_mut::<Data>().dao_admin.create_dao(info).await
 }
 
 #[update(guard = "is_owner")]
 #[candid::candid_method(update)]
-async fn update_dao_controller(action: ControllerAction) -> Result<(), String> {
+async fn refund_payment(block_height: u64) -> ManagerResult<u64> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .refund_payment(block_height)
+        .await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn my_dao_creations() -> Vec<DaoCreation> {
+    ic::get::<Data>().dao_admin.dao_creations(ic_cdk::caller())
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn update_dao_controller(action: ControllerAction) -> ManagerResult<()> {
     ic::get_mut::<Data>()
         .dao_admin
         .update_dao_controller(action)
@@ -102,19 +353,157 @@ fn get_owners() -> Vec<Principal> {
 
 #[update(guard = "is_owner")]
 #[candid::candid_method(update)]
-async fn upgrade_canister(cid: String) -> Result<(), (RejectionCode, String)> {
-    ic::get::<Data>().dao_admin.upgrade_canister(cid).await
+async fn upgrade_canister(
+    cid: String,
+    version: Option<String>,
+    arg: Option<Vec<u8>>,
+) -> ManagerResult<()> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .upgrade_canister(cid, version, arg)
+        .await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn upgrade_all(version: Option<String>, arg: Option<Vec<u8>>) -> ManagerResult<UpgradeBatch> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .upgrade_all(version, arg)
+        .await
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn upgrade_batches() -> Vec<UpgradeBatch> {
+    ic::get::<Data>().dao_admin.upgrade_batches()
+}
+
+#[query(guard = "is_owner")]
+#[candid::candid_method(query)]
+fn pending_restarts() -> Vec<Principal> {
+    ic::get::<Data>().dao_admin.pending_restarts()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn retry_restarts() -> Vec<Principal> {
+    ic::get_mut::<Data>().dao_admin.retry_restarts().await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn start_rollout(config: RolloutConfig) -> ManagerResult<Rollout> {
+    ic::get_mut::<Data>().rollout.start(config)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn rollout_step() -> ManagerResult<Rollout> {
+    ic::get_mut::<Data>().rollout.step().await
 }
 
 #[update(guard = "is_owner")]
 #[candid::candid_method(update)]
-async fn reinstall_canister(cid: String) -> Result<(), (RejectionCode, String)> {
-    ic::get::<Data>().dao_admin.reinstall_canister(cid).await
+fn resume_rollout() -> ManagerResult<Rollout> {
+    ic::get_mut::<Data>().rollout.resume()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn cancel_rollout() -> ManagerResult<Rollout> {
+    ic::get_mut::<Data>().rollout.cancel()
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn rollback_rollout(id: u64) -> ManagerResult<Rollout> {
+    ic::get_mut::<Data>().rollout.rollback(id).await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn rollback_dao(canister_id: Principal) -> ManagerResult<String> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .rollback_dao(canister_id)
+        .await
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn rollout_progress(id: Option<u64>) -> Option<Rollout> {
+    ic::get::<Data>().rollout.rollout(id)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+async fn reinstall_canister(
+    cid: String,
+    version: Option<String>,
+    arg: Option<Vec<u8>>,
+) -> ManagerResult<()> {
+    ic::get_mut::<Data>()
+        .dao_admin
+        .reinstall_canister(cid, version, arg)
+        .await
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn wasm_upload_begin(version: String, release_notes: String) -> ManagerResult<()> {
+    ic::get_mut::<Data>()
+        .wasm_store
+        .upload_begin(version, release_notes)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn wasm_upload_chunk(version: String, chunk: Vec<u8>) -> ManagerResult<u64> {
+    ic::get_mut::<Data>()
+        .wasm_store
+        .upload_chunk(version, chunk)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn wasm_upload_commit(
+    version: String,
+    sha256: Vec<u8>,
+    set_default: bool,
+) -> ManagerResult<WasmInfo> {
+    ic::get_mut::<Data>()
+        .wasm_store
+        .upload_commit(version, sha256, set_default)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn set_default_wasm(version: String) -> ManagerResult<()> {
+    ic::get_mut::<Data>().wasm_store.set_default(version)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn wasm_delete(version: String) -> ManagerResult<WasmInfo> {
+    ic::get_mut::<Data>().wasm_store.delete(version)
+}
+
+#[update(guard = "is_owner")]
+#[candid::candid_method(update)]
+fn wasm_prune_uploads(version: Option<String>) -> u64 {
+    ic::get_mut::<Data>().wasm_store.prune_uploads(version)
+}
+
+#[query]
+#[candid::candid_method(query)]
+fn wasm_list() -> Vec<WasmInfo> {
+    ic::get::<Data>().wasm_store.wasm_list()
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    let data = ic::get::<Data>();
+    let data = ic::get_mut::<Data>();
 
     let writer = StableWriter::default();
     serde_cbor::to_writer(
@@ -123,6 +512,12 @@ fn pre_upgrade() {
             owners: data.owners.clone(),
             dao_admin: data.dao_admin.clone(),
             icp_service: data.icp_service.clone(),
+            // modules can be large, the old instance doesn't need them anymore
+            wasm_store: std::mem::take(&mut data.wasm_store),
+            rollout: data.rollout.clone(),
+            plans: data.plans.clone(),
+            payments: data.payments.clone(),
+            treasury: data.treasury.clone(),
         },
     )
     .expect("Failed to serialize data.");
@@ -141,10 +536,20 @@ fn post_upgrade() {
         }
     };
 
+    let mut dao_admin = data.dao_admin;
+    dao_admin.migrate_legacy();
+    dao_admin.rebuild_tag_index();
+
     ic::store(Data {
         owners: data.owners,
-        dao_admin: data.dao_admin,
+        dao_admin,
         icp_service: data.icp_service,
+        wasm_store: data.wasm_store,
+        rollout: data.rollout,
+        plans: data.plans,
+        payments: data.payments,
+        treasury: data.treasury,
+        random: RandomService::default(),
     });
 }
 
@@ -0,0 +1,353 @@
+use crate::canister::dip20;
+use crate::canister::icrc::{self, Account, TransferArg, TransferFromArgs};
+use crate::error::{ManagerError, ManagerResult};
+use crate::plan::CreationPlan;
+use crate::Data;
+use candid::{CandidType, Deserialize, Nat, Principal};
+use ic_kit::ic;
+use ic_ledger_types::Subaccount;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// How an intent is paid for.
+#[derive(Deserialize, Serialize, Clone, Copy, CandidType, PartialEq, Debug)]
+pub enum PaymentMethod {
+    Icp,              // legacy ICP ledger, verified through `block_pb` or the intent subaccount
+    Icrc1(Principal), // ICRC-1 transfer into the intent subaccount on the token ledger
+    Icrc2(Principal), // ICRC-2 approval the manager collects with `icrc2_transfer_from`
+    Dip20(Principal), // DIP20 approval the manager collects with `transferFrom`
+}
+
+impl Default for PaymentMethod {
+    fn default() -> Self {
+        PaymentMethod::Icp
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct IcrcToken {
+    pub ledger: Principal,
+    pub symbol: String,
+    pub prices: BTreeMap<u64, u64>, // plan id to price, in the token's smallest unit
+    pub icrc2: bool,                // the ledger supports `icrc2_transfer_from`
+    pub enabled: bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct Dip20Token {
+    pub canister: Principal,
+    pub symbol: String,             // taken from `getMetadata` when whitelisted
+    pub decimals: u8,               // must match `getMetadata` for payments to be collected
+    pub prices: BTreeMap<u64, u64>, // plan id to price, in the token's smallest unit
+    pub enabled: bool,
+}
+
+/// ICRC and DIP20 tokens owners accept as payment besides ICP.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct PaymentService {
+    #[serde(default)]
+    pub tokens: BTreeMap<Principal, IcrcToken>,
+    #[serde(default)]
+    pub dip20_tokens: BTreeMap<Principal, Dip20Token>,
+}
+
+impl PaymentService {
+    pub fn set_token(&mut self, token: IcrcToken) -> ManagerResult<IcrcToken> {
+        if token.prices.values().any(|price| *price == 0) {
+            return Err(ManagerError::InvalidPlan(
+                "price must be positive".to_owned(),
+            ));
+        }
+        self.tokens.insert(token.ledger, token.clone());
+        Ok(token)
+    }
+    pub fn token_list(&self) -> Vec<IcrcToken> {
+        self.tokens.values().cloned().collect()
+    }
+    /// Whitelist a DIP20 token once its metadata agrees with `token.decimals`.
+    pub async fn set_dip20_token(&mut self, mut token: Dip20Token) -> ManagerResult<Dip20Token> {
+        if token.prices.values().any(|price| *price == 0) {
+            return Err(ManagerError::InvalidPlan(
+                "price must be positive".to_owned(),
+            ));
+        }
+        let (metadata,) = dip20::SERVICE::new(token.canister)
+            .getMetadata()
+            .await
+            .map_err(ManagerError::rejected_by(token.canister))?;
+        if metadata.decimals != token.decimals {
+            return Err(ManagerError::InvalidToken(format!(
+                "{} has {} decimals",
+                metadata.symbol, metadata.decimals
+            )));
+        }
+        token.symbol = metadata.symbol;
+        self.dip20_tokens.insert(token.canister, token.clone());
+        Ok(token)
+    }
+    pub fn dip20_token_list(&self) -> Vec<Dip20Token> {
+        self.dip20_tokens.values().cloned().collect()
+    }
+    /// Balance of the manager's main account on a whitelisted ICRC or DIP20
+    /// token, disabled ones included.
+    pub async fn token_balance(&self, token: Principal) -> ManagerResult<u64> {
+        let result = if self.tokens.contains_key(&token) {
+            icrc::SERVICE::new(token)
+                .icrc1_balance_of(manager_account())
+                .await
+        } else if self.dip20_tokens.contains_key(&token) {
+            dip20::SERVICE::new(token)
+                .balanceOf(ic_cdk::api::id())
+                .await
+        } else {
+            return Err(ManagerError::TokenNotFound);
+        };
+        let (balance,) = result.map_err(ManagerError::rejected_by(token))?;
+        nat_to_u64(&balance)
+    }
+    /// Transfer fee of a whitelisted ICRC or DIP20 token.
+    pub async fn token_fee(&self, token: Principal) -> ManagerResult<u64> {
+        let fee = if self.tokens.contains_key(&token) {
+            icrc::SERVICE::new(token)
+                .icrc1_fee()
+                .await
+                .map_err(ManagerError::rejected_by(token))?
+                .0
+        } else if self.dip20_tokens.contains_key(&token) {
+            dip20::SERVICE::new(token)
+                .getMetadata()
+                .await
+                .map_err(ManagerError::rejected_by(token))?
+                .0
+                .fee
+        } else {
+            return Err(ManagerError::TokenNotFound);
+        };
+        nat_to_u64(&fee)
+    }
+    /// Send `amount` of a whitelisted token from the manager's main account,
+    /// returning the block on the token ledger. The fee is paid on top.
+    pub async fn transfer_token(
+        &self,
+        token: Principal,
+        to: Account,
+        amount: u64,
+    ) -> ManagerResult<u64> {
+        if self.tokens.contains_key(&token) {
+            let args = TransferArg {
+                from_subaccount: None,
+                to,
+                amount: Nat::from(amount),
+                fee: None,
+                memo: None,
+                created_at_time: None,
+            };
+            let (result,) = icrc::SERVICE::new(token)
+                .icrc1_transfer(args)
+                .await
+                .map_err(ManagerError::rejected_by(token))?;
+            let index = result.map_err(|e| ManagerError::LedgerError(format!("{:?}", e)))?;
+            return nat_to_u64(&index);
+        }
+        if !self.dip20_tokens.contains_key(&token) {
+            return Err(ManagerError::TokenNotFound);
+        }
+        if to.subaccount.is_some() {
+            return Err(ManagerError::InvalidToken(
+                "DIP20 tokens have no subaccounts".to_owned(),
+            ));
+        }
+        let (result,) = dip20::SERVICE::new(token)
+            .transfer(to.owner, Nat::from(amount))
+            .await
+            .map_err(ManagerError::rejected_by(token))?;
+        match result {
+            dip20::Result::Ok(index) => nat_to_u64(&index),
+            dip20::Result::Err(err) => Err(ManagerError::LedgerError(format!("{:?}", err))),
+        }
+    }
+    /// Price of `plan` when paid with `method`.
+    pub fn price(&self, method: PaymentMethod, plan: &CreationPlan) -> ManagerResult<u64> {
+        let ledger = match method {
+            PaymentMethod::Icp => return Ok(plan.price),
+            PaymentMethod::Icrc1(ledger) | PaymentMethod::Icrc2(ledger) => ledger,
+            PaymentMethod::Dip20(canister) => {
+                let token = self
+                    .dip20_tokens
+                    .get(&canister)
+                    .filter(|token| token.enabled)
+                    .ok_or(ManagerError::TokenNotFound)?;
+                return token.prices.get(&plan.id).copied().ok_or_else(|| {
+                    ManagerError::InvalidPlan(format!(
+                        "plan {} has no {} price",
+                        plan.id, token.symbol
+                    ))
+                });
+            }
+        };
+        let token = self
+            .tokens
+            .get(&ledger)
+            .filter(|token| token.enabled)
+            .ok_or(ManagerError::TokenNotFound)?;
+        if let PaymentMethod::Icrc2(_) = method {
+            if !token.icrc2 {
+                return Err(ManagerError::PaymentMismatch(format!(
+                    "{} does not support transfer_from",
+                    token.symbol
+                )));
+            }
+        }
+        token.prices.get(&plan.id).copied().ok_or_else(|| {
+            ManagerError::InvalidPlan(format!("plan {} has no {} price", plan.id, token.symbol))
+        })
+    }
+}
+
+impl PaymentMethod {
+    /// Verify or collect a token payment of `amount`, returning its block on
+    /// the token ledger.
+    pub async fn collect(
+        self,
+        payer: Principal,
+        subaccount: Option<Subaccount>,
+        amount: u64,
+        memo: u64,
+    ) -> ManagerResult<u64> {
+        match self {
+            PaymentMethod::Icp => Err(ManagerError::PaymentMismatch(
+                "ICP payments are verified by the ledger service".to_owned(),
+            )),
+            PaymentMethod::Icrc1(ledger) => {
+                let from = Account {
+                    owner: ic_cdk::api::id(),
+                    subaccount: subaccount.map(|subaccount| subaccount.0.to_vec()),
+                };
+                sweep_icrc1(ledger, from, amount, memo).await
+            }
+            PaymentMethod::Icrc2(ledger) => {
+                let args = TransferFromArgs {
+                    spender_subaccount: None,
+                    from: Account {
+                        owner: payer,
+                        subaccount: None,
+                    },
+                    to: manager_account(),
+                    amount: Nat::from(amount),
+                    fee: None,
+                    memo: Some(memo.to_be_bytes().to_vec()),
+                    created_at_time: None,
+                };
+                let (result,) = icrc::SERVICE::new(ledger)
+                    .icrc2_transfer_from(args)
+                    .await
+                    .map_err(ManagerError::rejected_by(ledger))?;
+                let index = result.map_err(|e| ManagerError::LedgerError(format!("{:?}", e)))?;
+                nat_to_u64(&index)
+            }
+            PaymentMethod::Dip20(canister) => collect_dip20(canister, payer, amount).await,
+        }
+    }
+}
+
+/// Move everything paid into an intent subaccount to the manager's main
+/// account, returning the block of the sweep. Only ICRC-1 methods are used,
+/// so this also works with the ICP ledger's ICRC interface.
+async fn sweep_icrc1(
+    ledger: Principal,
+    from: Account,
+    price: u64,
+    memo: u64,
+) -> ManagerResult<u64> {
+    let service = icrc::SERVICE::new(ledger);
+    let (balance,) = service
+        .icrc1_balance_of(from.clone())
+        .await
+        .map_err(ManagerError::rejected_by(ledger))?;
+    let (fee,) = service
+        .icrc1_fee()
+        .await
+        .map_err(ManagerError::rejected_by(ledger))?;
+    if balance < Nat::from(price) || balance <= fee {
+        return Err(ManagerError::PaymentMismatch(format!(
+            "subaccount holds {} of {}",
+            balance, price
+        )));
+    }
+    let args = TransferArg {
+        from_subaccount: from.subaccount,
+        to: manager_account(),
+        amount: balance - fee.clone(),
+        fee: Some(fee),
+        memo: Some(memo.to_be_bytes().to_vec()),
+        created_at_time: None,
+    };
+    let (result,) = service
+        .icrc1_transfer(args)
+        .await
+        .map_err(ManagerError::rejected_by(ledger))?;
+    let index = result.map_err(|e| ManagerError::LedgerError(format!("{:?}", e)))?;
+    nat_to_u64(&index)
+}
+
+/// Pull `amount` from `payer` with DIP20 `transferFrom`, returning the
+/// transaction index on the token canister.
+async fn collect_dip20(canister: Principal, payer: Principal, amount: u64) -> ManagerResult<u64> {
+    let decimals = ic::get::<Data>()
+        .payments
+        .dip20_tokens
+        .get(&canister)
+        .map(|token| token.decimals)
+        .ok_or(ManagerError::TokenNotFound)?;
+    let service = dip20::SERVICE::new(canister);
+    let (metadata,) = service
+        .getMetadata()
+        .await
+        .map_err(ManagerError::rejected_by(canister))?;
+    if metadata.decimals != decimals {
+        return Err(ManagerError::InvalidToken(format!(
+            "{} now has {} decimals, prices need to be updated",
+            metadata.symbol, metadata.decimals
+        )));
+    }
+    // the fee is charged to the payer on top of the amount
+    let required = Nat::from(amount) + metadata.fee;
+    let (allowance,) = service
+        .allowance(payer, ic_cdk::api::id())
+        .await
+        .map_err(ManagerError::rejected_by(canister))?;
+    if allowance < required {
+        return Err(ManagerError::PaymentMismatch(format!(
+            "approve at least {} {} including the fee",
+            required, metadata.symbol
+        )));
+    }
+
+    let (result,) = service
+        .transferFrom(payer, ic_cdk::api::id(), Nat::from(amount))
+        .await
+        .map_err(ManagerError::rejected_by(canister))?;
+    match result {
+        dip20::Result::Ok(index) => nat_to_u64(&index),
+        dip20::Result::Err(err) => Err(ManagerError::LedgerError(format!("{:?}", err))),
+    }
+}
+
+/// The manager's own account on token ledgers, where collected payments end up.
+fn manager_account() -> Account {
+    Account {
+        owner: ic_cdk::api::id(),
+        subaccount: None,
+    }
+}
+
+fn nat_to_u64(nat: &Nat) -> ManagerResult<u64> {
+    match nat.0.to_u64_digits().as_slice() {
+        [] => Ok(0),
+        [digit] => Ok(*digit),
+        _ => Err(ManagerError::LedgerError(format!(
+            "{} does not fit into u64",
+            nat
+        ))),
+    }
+}
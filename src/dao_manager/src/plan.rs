@@ -0,0 +1,79 @@
+use crate::canister_manager::CREATE_CYCLES;
+use crate::dao_admin::resolve_version;
+use crate::error::{ManagerError, ManagerResult};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Serialize, Clone, CandidType, Debug)]
+pub struct CreationPlan {
+    pub id: u64,
+    pub name: String,
+    pub price: u64,                      // e8s
+    pub cycles: u64,                     // cycles the dao canister starts with, at least 1T
+    pub compute_allocation: Option<u64>, // percent
+    pub memory_allocation: Option<u64>,  // bytes
+    pub wasm_version: Option<String>,    // None installs the default version
+    pub enabled: bool,                   // disabled plans can no longer be paid for
+}
+
+/// Creation plans users pick from in `get_pay_info`. Plans are never removed,
+/// only disabled, so paid intents can always be fulfilled.
+#[derive(Deserialize, Serialize, Clone)]
+pub struct PlanService {
+    #[serde(default)]
+    pub plans: BTreeMap<u64, CreationPlan>,
+}
+
+impl Default for PlanService {
+    fn default() -> Self {
+        let basic = CreationPlan {
+            id: 0,
+            name: "basic".to_owned(),
+            price: 10_000_000, // 0.1 ICP
+            cycles: 1_000_000_000_000,
+            compute_allocation: None,
+            memory_allocation: None,
+            wasm_version: None,
+            enabled: true,
+        };
+        PlanService {
+            plans: vec![(basic.id, basic)].into_iter().collect(),
+        }
+    }
+}
+
+impl PlanService {
+    pub fn set_plan(&mut self, plan: CreationPlan) -> ManagerResult<CreationPlan> {
+        if plan.price == 0 {
+            return Err(ManagerError::InvalidPlan(
+                "price must be positive".to_owned(),
+            ));
+        }
+        if (plan.cycles as u128) < CREATE_CYCLES {
+            return Err(ManagerError::InvalidPlan(format!(
+                "cycles must be at least {}, the canister is created with them",
+                CREATE_CYCLES
+            )));
+        }
+        if plan.compute_allocation.unwrap_or(0) > 100 {
+            return Err(ManagerError::InvalidPlan(
+                "compute_allocation is a percentage".to_owned(),
+            ));
+        }
+        if let Some(version) = &plan.wasm_version {
+            resolve_version(Some(version.clone()))?;
+        }
+        self.plans.insert(plan.id, plan.clone());
+        Ok(plan)
+    }
+    pub fn plan_list(&self) -> Vec<CreationPlan> {
+        self.plans.values().cloned().collect()
+    }
+    pub fn get_plan(&self, id: u64) -> ManagerResult<CreationPlan> {
+        self.plans
+            .get(&id)
+            .cloned()
+            .ok_or(ManagerError::PlanNotFound)
+    }
+}
@@ -0,0 +1,53 @@
+use crate::error::ManagerResult;
+use crate::tool::subnet_raw_rand;
+use crate::Data;
+use ic_kit::ic;
+use rand_chacha::rand_core::{RngCore, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Time between two reseeds from the management canister, one hour in nanoseconds.
+const RESEED_INTERVAL: u64 = 60 * 60 * 1_000_000_000;
+
+/// CSPRNG seeded from `raw_rand`, so memos, subaccounts and nonces don't need
+/// an inter-canister call each. The generator is not persisted across upgrades;
+/// the first request or heartbeat after an upgrade seeds it again.
+#[derive(Default)]
+pub struct RandomService {
+    rng: Option<ChaCha20Rng>,
+    last_reseed: u64,
+    reseeding: bool,
+}
+
+impl RandomService {
+    pub async fn next_u64(&mut self) -> ManagerResult<u64> {
+        Ok(self.rng().await?.next_u64())
+    }
+    pub async fn bytes(&mut self) -> ManagerResult<[u8; 32]> {
+        let mut bytes = [0u8; 32];
+        self.rng().await?.fill_bytes(&mut bytes);
+        Ok(bytes)
+    }
+    pub async fn reseed(&mut self) -> ManagerResult<()> {
+        self.reseeding = true;
+        let seed = subnet_raw_rand().await;
+        self.reseeding = false;
+
+        self.rng = Some(ChaCha20Rng::from_seed(seed?));
+        self.last_reseed = ic_cdk::api::time();
+        Ok(())
+    }
+    pub fn heartbeat(&mut self) {
+        let due = self.rng.is_none() || ic_cdk::api::time() >= self.last_reseed + RESEED_INTERVAL;
+        if due && !self.reseeding {
+            ic_cdk::spawn(async {
+                let _ = ic::get_mut::<Data>().random.reseed().await;
+            });
+        }
+    }
+    async fn rng(&mut self) -> ManagerResult<&mut ChaCha20Rng> {
+        if self.rng.is_none() {
+            self.reseed().await?;
+        }
+        Ok(self.rng.as_mut().expect("seeded above"))
+    }
+}
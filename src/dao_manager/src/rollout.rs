@@ -0,0 +1,390 @@
+use crate::dao_admin::{resolve_version, upgrade_result};
+use crate::error::{ManagerError, ManagerResult};
+use crate::types::{DaoStatusCode, UpgradeResult};
+use crate::Data;
+use candid::{CandidType, Deserialize, Principal};
+use ic_kit::ic;
+use serde::Serialize;
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct RolloutConfig {
+    pub version: Option<String>, // defaults to the wasm store's default version
+    pub canary_size: u64,        // daos upgraded in the first stage
+    pub stage_percents: Vec<u8>, // cumulative registry share per later stage, e.g. [10, 50]
+    pub max_failure_percent: u8, // pause when a stage fails above this ratio
+    pub batch_size: u64,         // upgrades per step
+    pub use_heartbeat: bool,     // let the heartbeat drive the rollout
+    #[serde(default)]
+    pub arg: Option<Vec<u8>>, // candid encoded upgrade argument
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType, PartialEq)]
+pub enum RolloutStatus {
+    Running,
+    Paused(String),
+    Completed,
+    Cancelled,
+    RolledBack,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct Rollout {
+    pub id: u64,
+    pub version: String,
+    pub config: RolloutConfig,
+    pub targets: Vec<Principal>,
+    pub stage_ends: Vec<u64>, // exclusive end index into `targets` of every stage
+    pub stage: u64,
+    pub results: Vec<UpgradeResult>, // one per processed target, in target order
+    #[serde(default)]
+    pub rollback_results: Vec<UpgradeResult>,
+    pub status: RolloutStatus,
+    pub started_at: u64,
+    pub updated_at: u64,
+}
+
+impl Rollout {
+    fn is_active(&self) -> bool {
+        matches!(
+            self.status,
+            RolloutStatus::Running | RolloutStatus::Paused(_)
+        )
+    }
+    fn stage_start(&self) -> usize {
+        match self.stage {
+            0 => 0,
+            stage => self.stage_ends[stage as usize - 1] as usize,
+        }
+    }
+    fn stage_end(&self) -> usize {
+        self.stage_ends[self.stage as usize] as usize
+    }
+    /// Close the current stage, pausing if too many upgrades failed in it.
+    fn finish_stage(&mut self) {
+        let stage_results = &self.results[self.stage_start()..];
+        let failed = stage_results
+            .iter()
+            .filter(|result| !result.success && !result.skipped)
+            .count();
+        if failed * 100 > self.config.max_failure_percent as usize * stage_results.len() {
+            self.status = RolloutStatus::Paused(format!(
+                "stage {} failed {} of {} upgrades",
+                self.stage,
+                failed,
+                stage_results.len()
+            ));
+        } else {
+            self.advance();
+        }
+    }
+    fn advance(&mut self) {
+        if self.stage as usize + 1 >= self.stage_ends.len() {
+            self.status = RolloutStatus::Completed;
+        } else {
+            self.stage += 1;
+            self.status = RolloutStatus::Running;
+        }
+    }
+}
+
+/// Cumulative stage boundaries: the canary cohort, then every percentage of
+/// `total`, always ending with the whole registry.
+fn stage_ends(config: &RolloutConfig, total: u64) -> Vec<u64> {
+    let mut ends = vec![config.canary_size.min(total)];
+    let percents = config.stage_percents.iter().chain(std::iter::once(&100));
+    for percent in percents {
+        let end = ((total * (*percent).min(100) as u64) + 99) / 100;
+        if end > *ends.last().unwrap_or(&0) {
+            ends.push(end);
+        }
+    }
+    if ends.len() > 1 && ends[0] == 0 {
+        ends.remove(0);
+    }
+    ends
+}
+
+/// Staged upgrades of the dao fleet, advanced by `step` from update calls
+/// or the heartbeat.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct RolloutService {
+    #[serde(default)]
+    pub rollouts: Vec<Rollout>,
+}
+
+impl RolloutService {
+    pub fn start(&mut self, config: RolloutConfig) -> ManagerResult<Rollout> {
+        if self.rollouts.iter().any(Rollout::is_active) {
+            return Err(ManagerError::InvalidRollout(
+                "another rollout is still active".to_owned(),
+            ));
+        }
+        if config.batch_size == 0 || config.max_failure_percent > 100 {
+            return Err(ManagerError::InvalidRollout(
+                "batch_size must be positive and max_failure_percent at most 100".to_owned(),
+            ));
+        }
+        let version = resolve_version(config.version.clone())?;
+        let targets: Vec<Principal> = ic::get::<Data>()
+            .dao_admin
+            .daos
+            .values()
+            .filter(|dao| dao.status != DaoStatusCode::Archived)
+            .map(|dao| dao.canister_id)
+            .collect();
+        let now = ic_cdk::api::time();
+        let rollout = Rollout {
+            id: self.rollouts.len() as u64,
+            version,
+            stage_ends: stage_ends(&config, targets.len() as u64),
+            config,
+            targets,
+            stage: 0,
+            results: vec![],
+            rollback_results: vec![],
+            status: RolloutStatus::Running,
+            started_at: now,
+            updated_at: now,
+        };
+        self.rollouts.push(rollout.clone());
+        Ok(rollout)
+    }
+    /// Upgrade the next batch of the current stage.
+    pub async fn step(&mut self) -> ManagerResult<Rollout> {
+        let index = self.active_index()?;
+        let rollout = &self.rollouts[index];
+        if rollout.status != RolloutStatus::Running {
+            return Ok(rollout.clone());
+        }
+
+        let start = rollout.results.len();
+        let end = rollout
+            .stage_end()
+            .min(start + rollout.config.batch_size as usize);
+        let targets = rollout.targets[start..end].to_vec();
+        let version = rollout.version.clone();
+        let arg = rollout.config.arg.clone().unwrap_or_default();
+
+        // shared with `upgrade_all`, both stop and upgrade daos
+        ic::get_mut::<Data>().dao_admin.begin_batch()?;
+        for canister_id in targets {
+            // the rollout may be cancelled while we await
+            if self.rollouts[index].status != RolloutStatus::Running {
+                break;
+            }
+            let result = ic::get_mut::<Data>()
+                .dao_admin
+                .upgrade_dao(canister_id, &version, arg.clone())
+                .await;
+            self.rollouts[index]
+                .results
+                .push(upgrade_result(canister_id, result));
+        }
+        ic::get_mut::<Data>().dao_admin.end_batch();
+
+        let rollout = &mut self.rollouts[index];
+        if rollout.status == RolloutStatus::Running && rollout.results.len() >= rollout.stage_end()
+        {
+            rollout.finish_stage();
+        }
+        rollout.updated_at = ic_cdk::api::time();
+        Ok(rollout.clone())
+    }
+    /// Continue a paused rollout with its next stage.
+    pub fn resume(&mut self) -> ManagerResult<Rollout> {
+        let index = self.active_index()?;
+        let rollout = &mut self.rollouts[index];
+        if let RolloutStatus::Paused(_) = rollout.status {
+            if rollout.results.len() >= rollout.stage_end() {
+                rollout.advance();
+            } else {
+                rollout.status = RolloutStatus::Running;
+            }
+            rollout.updated_at = ic_cdk::api::time();
+        }
+        Ok(rollout.clone())
+    }
+    pub fn cancel(&mut self) -> ManagerResult<Rollout> {
+        let index = self.active_index()?;
+        let rollout = &mut self.rollouts[index];
+        rollout.status = RolloutStatus::Cancelled;
+        rollout.updated_at = ic_cdk::api::time();
+        Ok(rollout.clone())
+    }
+    /// Roll every dao this rollout upgraded back to its previous version.
+    /// Daos that have since moved to another version are left alone.
+    pub async fn rollback(&mut self, id: u64) -> ManagerResult<Rollout> {
+        let index = id as usize;
+        if index >= self.rollouts.len() {
+            return Err(ManagerError::RolloutNotFound);
+        }
+        ic::get_mut::<Data>().dao_admin.begin_batch()?;
+        let rollout = &mut self.rollouts[index];
+        rollout.status = RolloutStatus::RolledBack;
+        rollout.rollback_results.clear();
+        let version = rollout.version.clone();
+        let upgraded: Vec<Principal> = rollout
+            .results
+            .iter()
+            .filter(|result| result.success)
+            .map(|result| result.canister_id)
+            .collect();
+
+        for canister_id in upgraded {
+            let dao_admin = &mut ic::get_mut::<Data>().dao_admin;
+            let installed = dao_admin
+                .daos
+                .get(&canister_id)
+                .and_then(|dao| dao.wasm_version.clone());
+            if installed.as_ref() != Some(&version) {
+                continue;
+            }
+            let result = dao_admin.rollback_dao(canister_id).await.map(|_| ());
+            self.rollouts[index]
+                .rollback_results
+                .push(upgrade_result(canister_id, result));
+        }
+        ic::get_mut::<Data>().dao_admin.end_batch();
+
+        let rollout = &mut self.rollouts[index];
+        rollout.updated_at = ic_cdk::api::time();
+        Ok(rollout.clone())
+    }
+    pub fn rollout(&self, id: Option<u64>) -> Option<Rollout> {
+        match id {
+            Some(id) => self.rollouts.get(id as usize).cloned(),
+            None => self.rollouts.last().cloned(),
+        }
+    }
+    pub fn heartbeat(&mut self) {
+        let running = self.rollouts.iter().any(|rollout| {
+            rollout.status == RolloutStatus::Running && rollout.config.use_heartbeat
+        });
+        if running && !ic::get::<Data>().dao_admin.batch_running() {
+            ic_cdk::spawn(async {
+                let _ = ic::get_mut::<Data>().rollout.step().await;
+            });
+        }
+    }
+    fn active_index(&self) -> ManagerResult<usize> {
+        self.rollouts
+            .iter()
+            .rposition(Rollout::is_active)
+            .ok_or(ManagerError::NoActiveRollout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(canary_size: u64, stage_percents: Vec<u8>, max_failure_percent: u8) -> RolloutConfig {
+        RolloutConfig {
+            version: None,
+            canary_size,
+            stage_percents,
+            max_failure_percent,
+            batch_size: 10,
+            use_heartbeat: false,
+            arg: None,
+        }
+    }
+
+    fn result(success: bool) -> UpgradeResult {
+        UpgradeResult {
+            canister_id: Principal::anonymous(),
+            success,
+            rejection_code: None,
+            skipped: false,
+            message: String::new(),
+            finished_at: 0,
+        }
+    }
+
+    fn rollout(
+        stage_ends: Vec<u64>,
+        max_failure_percent: u8,
+        results: Vec<UpgradeResult>,
+    ) -> Rollout {
+        Rollout {
+            id: 0,
+            version: "1.0.0".to_owned(),
+            config: config(1, vec![], max_failure_percent),
+            targets: vec![],
+            stage_ends,
+            stage: 0,
+            results,
+            rollback_results: vec![],
+            status: RolloutStatus::Running,
+            started_at: 0,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn stage_ends_with_canary_and_percents() {
+        assert_eq!(
+            stage_ends(&config(2, vec![10, 50], 0), 40),
+            vec![2, 4, 20, 40]
+        );
+    }
+
+    #[test]
+    fn stage_ends_without_canary() {
+        assert_eq!(stage_ends(&config(0, vec![50], 0), 10), vec![5, 10]);
+        assert_eq!(stage_ends(&config(0, vec![], 0), 10), vec![10]);
+    }
+
+    #[test]
+    fn stage_ends_of_empty_registry() {
+        assert_eq!(stage_ends(&config(3, vec![10, 50], 0), 0), vec![0]);
+    }
+
+    #[test]
+    fn stage_ends_skips_percents_out_of_order_or_above_100() {
+        assert_eq!(stage_ends(&config(1, vec![50, 10], 0), 10), vec![1, 5, 10]);
+        assert_eq!(stage_ends(&config(1, vec![150, 20], 0), 10), vec![1, 10]);
+    }
+
+    #[test]
+    fn stage_ends_caps_canary_at_total() {
+        assert_eq!(stage_ends(&config(20, vec![50], 0), 5), vec![5]);
+    }
+
+    #[test]
+    fn finish_stage_advances_at_failure_ratio() {
+        let mut rollout = rollout(vec![2, 4], 50, vec![result(true), result(false)]);
+        rollout.finish_stage();
+        assert_eq!(rollout.stage, 1);
+        assert!(rollout.status == RolloutStatus::Running);
+    }
+
+    #[test]
+    fn finish_stage_pauses_above_failure_ratio() {
+        let mut rollout = rollout(
+            vec![3, 4],
+            50,
+            vec![result(true), result(false), result(false)],
+        );
+        rollout.finish_stage();
+        assert_eq!(rollout.stage, 0);
+        assert!(matches!(rollout.status, RolloutStatus::Paused(_)));
+    }
+
+    #[test]
+    fn finish_stage_ignores_skipped_upgrades() {
+        let mut skipped = result(false);
+        skipped.skipped = true;
+        let mut rollout = rollout(vec![2, 4], 0, vec![result(true), skipped]);
+        rollout.finish_stage();
+        assert_eq!(rollout.stage, 1);
+    }
+
+    #[test]
+    fn finish_stage_completes_last_stage() {
+        let mut rollout = rollout(vec![1, 2], 0, vec![result(true), result(true)]);
+        rollout.stage = 1;
+        rollout.finish_stage();
+        assert!(rollout.status == RolloutStatus::Completed);
+    }
+}
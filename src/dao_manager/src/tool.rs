@@ -1,3 +1,4 @@
+use crate::error::ManagerResult;
 use ic_cdk::export::Principal;
 
 pub fn encode_token(canister_id: Principal, token: u32) -> String {
@@ -60,14 +61,13 @@ pub fn decode_token(mut token: String) -> Result<(Principal, u32), String> {
     }
 }
 
-pub async fn subnet_raw_rand() -> Result<u64, String> {
+pub async fn subnet_raw_rand() -> ManagerResult<[u8; 32]> {
     let management_canister = ic_cdk::export::Principal::management_canister();
-    let rnd_buffer: (Vec<u8>,) = match ic_cdk::call(management_canister, "raw_rand", ()).await {
-        Ok(result) => result,
-        Err(err) => {
-            return Err(err.1);
-        }
-    };
+    let rnd_buffer: (Vec<u8>,) = ic_cdk::call(management_canister, "raw_rand", ()).await?;
 
-    Ok(rnd_buffer.0[0] as u64)
+    let mut seed = [0u8; 32];
+    for (seed_byte, byte) in seed.iter_mut().zip(rnd_buffer.0) {
+        *seed_byte = byte;
+    }
+    Ok(seed)
 }
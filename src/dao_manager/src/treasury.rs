@@ -0,0 +1,371 @@
+use crate::canister::icrc::Account;
+use crate::error::{ManagerError, ManagerResult};
+use crate::Data;
+use candid::{CandidType, Deserialize, Principal};
+use ic_kit::ic;
+use ic_ledger_types::{AccountIdentifier, Memo, Tokens, TransferArgs, DEFAULT_FEE};
+use serde::Serialize;
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct Beneficiary {
+    pub account: AccountIdentifier,
+    pub share: u8, // percent of every split
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType, Default)]
+pub struct TreasuryConfig {
+    pub beneficiaries: Vec<Beneficiary>, // shares add up to 100, empty disables splits
+    pub split_interval: Option<u64>,     // ns between heartbeat splits, None splits on request only
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct TreasuryBalance {
+    pub balance: u64,   // e8s in the receiving account
+    pub reserved: u64,  // verified payments still owed a dao or a refund
+    pub available: u64, // what can be withdrawn
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct Withdrawal {
+    pub id: u64,
+    pub to: AccountIdentifier,
+    pub amount: u64, // e8s received by `to`, the fee is paid on top
+    pub memo: u64,
+    pub block_height: Option<u64>, // None when the transfer failed
+    pub error: Option<String>,
+    pub requested_by: Principal,
+    pub created_at: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct TokenWithdrawal {
+    pub id: u64,
+    pub token: Principal, // ICRC ledger or DIP20 canister
+    pub to: Account,
+    pub amount: u64, // smallest token unit received by `to`, the fee is paid on top
+    pub block_height: Option<u64>, // None when the transfer failed
+    pub error: Option<String>,
+    pub requested_by: Principal,
+    pub created_at: u64,
+}
+
+/// ICP collected from creation payments, withdrawn by owners or split
+/// between beneficiaries. Token payments are only withdrawn by owners.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct TreasuryService {
+    #[serde(default)]
+    pub config: TreasuryConfig,
+    #[serde(default)]
+    pub withdrawals: Vec<Withdrawal>,
+    #[serde(default)]
+    pub token_withdrawals: Vec<TokenWithdrawal>,
+    #[serde(default)]
+    last_split: u64,
+    #[serde(skip)]
+    withdrawing: bool,
+}
+
+impl TreasuryService {
+    pub fn set_config(&mut self, config: TreasuryConfig) -> ManagerResult<TreasuryConfig> {
+        let shares: u64 = config
+            .beneficiaries
+            .iter()
+            .map(|beneficiary| beneficiary.share as u64)
+            .sum();
+        if !config.beneficiaries.is_empty() && shares != 100 {
+            return Err(ManagerError::InvalidTreasury(
+                "beneficiary shares must add up to 100".to_owned(),
+            ));
+        }
+        if config.split_interval == Some(0) {
+            return Err(ManagerError::InvalidTreasury(
+                "split_interval must be positive".to_owned(),
+            ));
+        }
+        self.config = config;
+        Ok(self.config.clone())
+    }
+    pub async fn balance(&self) -> ManagerResult<TreasuryBalance> {
+        let icp_service = &ic::get::<Data>().icp_service;
+        let ledger = icp_service.ledger.clone();
+        let reserved = icp_service.reserved();
+        let balance = ic_ledger_types::account_balance(
+            ledger.ledger_canister_id,
+            ic_ledger_types::AccountBalanceArgs {
+                account: ledger.receiving_account(),
+            },
+        )
+        .await
+        .map_err(ManagerError::rejected_by(ledger.ledger_canister_id))?
+        .e8s();
+        Ok(TreasuryBalance {
+            balance,
+            reserved,
+            available: balance.saturating_sub(reserved),
+        })
+    }
+    /// Send `amount` e8s from the receiving account to `to`.
+    pub async fn withdraw(
+        &mut self,
+        to: AccountIdentifier,
+        amount: u64,
+        memo: u64,
+        requested_by: Principal,
+    ) -> ManagerResult<Withdrawal> {
+        if self.withdrawing {
+            return Err(ManagerError::WithdrawalInProgress);
+        }
+        self.withdrawing = true;
+        let result = self.checked_transfer(to, amount, memo, requested_by).await;
+        self.withdrawing = false;
+        result
+    }
+    /// Split the available balance between the configured beneficiaries.
+    pub async fn split(
+        &mut self,
+        memo: u64,
+        requested_by: Principal,
+    ) -> ManagerResult<Vec<Withdrawal>> {
+        if self.config.beneficiaries.is_empty() {
+            return Err(ManagerError::InvalidTreasury(
+                "no beneficiaries are configured".to_owned(),
+            ));
+        }
+        if self.withdrawing {
+            return Err(ManagerError::WithdrawalInProgress);
+        }
+        self.withdrawing = true;
+        let result = self.split_available(memo, requested_by).await;
+        self.withdrawing = false;
+        self.last_split = ic_cdk::api::time();
+        result
+    }
+    pub fn withdrawals(&self) -> Vec<Withdrawal> {
+        self.withdrawals.clone()
+    }
+    /// Send `amount` of a whitelisted ICRC or DIP20 token collected from
+    /// payments to `to`. Payments for daos not created yet stay reserved.
+    pub async fn withdraw_token(
+        &mut self,
+        token: Principal,
+        to: Account,
+        amount: u64,
+        requested_by: Principal,
+    ) -> ManagerResult<TokenWithdrawal> {
+        if amount == 0 {
+            return Err(ManagerError::InvalidTreasury(
+                "amount must be positive".to_owned(),
+            ));
+        }
+        if self.withdrawing {
+            return Err(ManagerError::WithdrawalInProgress);
+        }
+        self.withdrawing = true;
+        // refused withdrawals are not logged, like `checked_transfer`
+        if let Err(err) = self.check_token_available(token, amount).await {
+            self.withdrawing = false;
+            return Err(err);
+        }
+        let result = ic::get::<Data>()
+            .payments
+            .transfer_token(token, to.clone(), amount)
+            .await;
+        self.withdrawing = false;
+
+        let withdrawal = TokenWithdrawal {
+            id: self.token_withdrawals.len() as u64,
+            token,
+            to,
+            amount,
+            block_height: result.as_ref().ok().copied(),
+            error: result.as_ref().err().map(|err| format!("{:?}", err)),
+            requested_by,
+            created_at: ic_cdk::api::time(),
+        };
+        self.token_withdrawals.push(withdrawal.clone());
+        result.map(|_| withdrawal)
+    }
+    pub fn token_withdrawals(&self) -> Vec<TokenWithdrawal> {
+        self.token_withdrawals.clone()
+    }
+    pub fn heartbeat(&mut self) {
+        let due = match self.config.split_interval {
+            Some(interval) => ic_cdk::api::time() >= self.last_split + interval,
+            None => false,
+        };
+        if due && !self.withdrawing && !self.config.beneficiaries.is_empty() {
+            // scheduled splits are requested by the manager itself
+            let requested_by = ic_cdk::api::id();
+            ic_cdk::spawn(async move {
+                let _ = ic::get_mut::<Data>().treasury.split(0, requested_by).await;
+            });
+        }
+    }
+    async fn checked_transfer(
+        &mut self,
+        to: AccountIdentifier,
+        amount: u64,
+        memo: u64,
+        requested_by: Principal,
+    ) -> ManagerResult<Withdrawal> {
+        let available = self.balance().await?.available;
+        if amount == 0 || amount.saturating_add(DEFAULT_FEE.e8s()) > available {
+            return Err(ManagerError::InvalidTreasury(format!(
+                "{} e8s are available, the transfer fee is {} e8s",
+                available,
+                DEFAULT_FEE.e8s()
+            )));
+        }
+        let withdrawal = self.transfer(to, amount, memo, requested_by).await;
+        match withdrawal.error {
+            Some(ref err) => Err(ManagerError::LedgerError(err.clone())),
+            None => Ok(withdrawal),
+        }
+    }
+    /// Check that sending `amount` of `token` leaves paid-for daos covered.
+    async fn check_token_available(&self, token: Principal, amount: u64) -> ManagerResult<()> {
+        let payments = &ic::get::<Data>().payments;
+        let balance = payments.token_balance(token).await?;
+        let fee = payments.token_fee(token).await?;
+        let reserved = ic::get::<Data>().icp_service.reserved_token(token);
+        let available = balance.saturating_sub(reserved);
+        if amount.saturating_add(fee) > available {
+            return Err(ManagerError::InvalidTreasury(format!(
+                "{} are available, the transfer fee is {}",
+                available, fee
+            )));
+        }
+        Ok(())
+    }
+    async fn split_available(
+        &mut self,
+        memo: u64,
+        requested_by: Principal,
+    ) -> ManagerResult<Vec<Withdrawal>> {
+        let available = self.balance().await?.available;
+        let payouts = split_amounts(available, &self.config.beneficiaries);
+        let mut withdrawals = vec![];
+        for (account, amount) in payouts {
+            let withdrawal = self.transfer(account, amount, memo, requested_by).await;
+            withdrawals.push(withdrawal);
+        }
+        Ok(withdrawals)
+    }
+    /// Transfer from the receiving account and log the attempt.
+    async fn transfer(
+        &mut self,
+        to: AccountIdentifier,
+        amount: u64,
+        memo: u64,
+        requested_by: Principal,
+    ) -> Withdrawal {
+        let ledger = ic::get::<Data>().icp_service.ledger.clone();
+        let args = TransferArgs {
+            memo: Memo(memo),
+            amount: Tokens::from_e8s(amount),
+            fee: DEFAULT_FEE,
+            from_subaccount: Some(ledger.receiving_subaccount),
+            to,
+            created_at_time: None,
+        };
+        let result = ic_ledger_types::transfer(ledger.ledger_canister_id, args)
+            .await
+            .map_err(ManagerError::rejected_by(ledger.ledger_canister_id))
+            .and_then(|res| res.map_err(|e| ManagerError::LedgerError(e.to_string())));
+
+        let withdrawal = Withdrawal {
+            id: self.withdrawals.len() as u64,
+            to,
+            amount,
+            memo,
+            block_height: result.as_ref().ok().copied(),
+            error: result.err().map(|err| format!("{:?}", err)),
+            requested_by,
+            created_at: ic_cdk::api::time(),
+        };
+        self.withdrawals.push(withdrawal.clone());
+        withdrawal
+    }
+}
+
+/// E8s each beneficiary receives from splitting `available`, the fee of
+/// every transfer taken out of its share.
+fn split_amounts(available: u64, beneficiaries: &[Beneficiary]) -> Vec<(AccountIdentifier, u64)> {
+    beneficiaries
+        .iter()
+        .map(|beneficiary| {
+            (
+                beneficiary.account,
+                available / 100 * beneficiary.share as u64,
+            )
+        })
+        // shares too small to pay their own fee wait for the next split
+        .filter(|(_, share)| *share > DEFAULT_FEE.e8s())
+        .map(|(account, share)| (account, share - DEFAULT_FEE.e8s()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ic_ledger_types::DEFAULT_SUBACCOUNT;
+
+    fn beneficiary(id: u8, share: u8) -> Beneficiary {
+        Beneficiary {
+            account: AccountIdentifier::new(&Principal::from_slice(&[id; 29]), &DEFAULT_SUBACCOUNT),
+            share,
+        }
+    }
+
+    #[test]
+    fn split_amounts_pay_shares_minus_fee() {
+        let beneficiaries = vec![beneficiary(1, 70), beneficiary(2, 30)];
+        let fee = DEFAULT_FEE.e8s();
+        assert_eq!(
+            split_amounts(1_000_000_000, &beneficiaries),
+            vec![
+                (beneficiaries[0].account, 700_000_000 - fee),
+                (beneficiaries[1].account, 300_000_000 - fee),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_amounts_round_down_to_whole_percents() {
+        let beneficiaries = vec![beneficiary(1, 50), beneficiary(2, 50)];
+        let fee = DEFAULT_FEE.e8s();
+        // the 99 e8s lost to rounding stay in the treasury
+        let amounts = split_amounts(10_000_199, &beneficiaries);
+        assert_eq!(amounts[0].1, 5_000_050 - fee);
+        assert_eq!(amounts[1].1, 5_000_050 - fee);
+    }
+
+    #[test]
+    fn split_amounts_skip_shares_not_covering_the_fee() {
+        let beneficiaries = vec![beneficiary(1, 99), beneficiary(2, 1)];
+        let fee = DEFAULT_FEE.e8s();
+        // 1% of 1_000_000 is exactly the fee
+        assert_eq!(
+            split_amounts(1_000_000, &beneficiaries),
+            vec![(beneficiaries[0].account, 990_000 - fee)]
+        );
+        assert!(split_amounts(fee, &beneficiaries).is_empty());
+        assert!(split_amounts(0, &beneficiaries).is_empty());
+    }
+
+    #[test]
+    fn set_config_requires_shares_adding_up_to_100() {
+        let mut treasury = TreasuryService::default();
+        let config = |beneficiaries| TreasuryConfig {
+            beneficiaries,
+            split_interval: None,
+        };
+        assert!(treasury
+            .set_config(config(vec![beneficiary(1, 60), beneficiary(2, 30)]))
+            .is_err());
+        assert!(treasury
+            .set_config(config(vec![beneficiary(1, 60), beneficiary(2, 40)]))
+            .is_ok());
+        assert!(treasury.set_config(config(vec![])).is_ok());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::plan::CreationPlan;
 use candid::{CandidType, Deserialize, Principal};
 use serde::Serialize;
 use std::collections::HashMap;
@@ -6,29 +7,226 @@ pub type CanisterIdText = String;
 
 #[derive(Deserialize, Serialize, Clone, CandidType)]
 pub struct DaoInfo {
-    pub canister_id: PrincipalText,
+    pub canister_id: Principal,
+    pub creator: Principal,
+    pub created_at: u64, // nanoseconds since epoch
+    #[serde(default)]
+    pub updated_at: u64, // last metadata change, nanoseconds since epoch
+    pub info: AddDaoInfo,
+    pub status: DaoStatusCode,
+    pub wasm_version: Option<String>, // None when installed outside the manager
+    #[serde(default)]
+    pub version_history: Vec<String>, // previously installed versions, oldest first
+    #[serde(default)]
+    pub upgrade_policy: UpgradePolicy,
+    #[serde(default)]
+    pub pending_upgrade: Option<PendingUpgrade>, // held back by the upgrade policy
+    #[serde(default)]
+    pub archived_by: Option<Principal>, // who archived the dao, owners' archives stick
+}
+
+/// How the manager may push new code to a dao, chosen by its controllers.
+#[derive(Deserialize, Serialize, Clone, CandidType, PartialEq)]
+pub enum UpgradePolicy {
+    Auto,           // upgraded by the manager without asking
+    NotifyOnly,     // only records the available upgrade
+    ManualApproval, // upgraded once a controller approves the pending upgrade
+    Frozen,         // never upgraded by the manager
+}
+
+impl Default for UpgradePolicy {
+    fn default() -> Self {
+        UpgradePolicy::Auto
+    }
+}
+
+/// What a held back install does once approved.
+#[derive(Deserialize, Serialize, Clone, Copy, CandidType, PartialEq)]
+pub enum UpgradeKind {
+    Upgrade,
+    Reinstall, // wipes the dao's state
+    Rollback,  // upgrade to the last version in `version_history`
+}
+
+impl Default for UpgradeKind {
+    fn default() -> Self {
+        UpgradeKind::Upgrade
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct PendingUpgrade {
+    pub version: String,
+    pub arg: Vec<u8>,
+    pub requested_at: u64,
+    #[serde(default)]
+    pub kind: UpgradeKind,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType, PartialEq)]
 pub enum DaoStatusCode {
     Active,
     Stopped,
+    Archived, // hidden from listings unless asked for
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub enum DaoSortKey {
+    CreatedAt,
+    Name,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, CandidType)]
+pub struct DaoListQuery {
+    pub offset: u64,
+    pub limit: u64,                    // capped at MAX_PAGE_SIZE
+    pub tag: Option<String>,           // exact tag match
+    pub status: Option<DaoStatusCode>, // exact status match
+    pub creator: Option<Principal>,
+    pub name: Option<String>, // case-insensitive substring
+    pub sort: Option<DaoSortKey>,
+    pub descending: bool,
+    pub include_archived: bool, // ignored when `status` is set
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct DaoPage {
+    pub total: u64, // number of daos matching the filters
+    pub offset: u64,
+    pub daos: Vec<DaoInfo>,
+}
+
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub enum TagMatch {
+    Any,
+    All,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: u64,
 }
 
 #[derive(Deserialize, Serialize, Default, Clone, CandidType)]
 pub struct CreateDaoOptions {
-    pub block_height: u64, // block height
+    pub block_height: u64, // legacy ICP payment block, ignored for subaccount and token intents
     pub memo: u64,         // memo, used to validate transfer
+    pub info: AddDaoInfo,  // dao metadata
 }
 
 #[derive(Deserialize, Serialize, Default, Clone, CandidType)]
 pub struct AddDaoInfo {
-    name: String,                            // dao name
-    poster: String,                          // dao poster
-    avatar: String,                          // dao avatar
-    pub tags: Vec<String>,                   // dao tags
-    intro: String,                           // dao intro
-    option: Option<HashMap<String, String>>, // user custom expand field
+    pub name: String,                            // dao name
+    pub poster: String,                          // dao poster
+    pub avatar: String,                          // dao avatar
+    pub tags: Vec<String>,                       // dao tags
+    pub intro: String,                           // dao intro
+    pub option: Option<HashMap<String, String>>, // user custom expand field
+}
+
+const MAX_NAME_LEN: usize = 64;
+const MAX_URL_LEN: usize = 512;
+const MAX_INTRO_LEN: usize = 2_000;
+const MAX_TAGS: usize = 10;
+const MAX_TAG_LEN: usize = 32;
+const MAX_OPTIONS: usize = 20;
+const MAX_OPTION_LEN: usize = 1_024;
+
+fn validate_url(field: &str, url: &str) -> Result<(), String> {
+    if url.is_empty() {
+        return Ok(());
+    }
+    if url.len() > MAX_URL_LEN {
+        return Err(format!("{} exceeds {} bytes", field, MAX_URL_LEN));
+    }
+    match url.strip_prefix("https://") {
+        Some(rest) if !rest.is_empty() && !rest.contains(char::is_whitespace) => Ok(()),
+        _ => Err(format!("{} must be an https url", field)),
+    }
+}
+
+impl AddDaoInfo {
+    pub fn validate(&self) -> Result<(), String> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Err(format!("name must be 1 to {} characters", MAX_NAME_LEN));
+        }
+        validate_url("poster", &self.poster)?;
+        validate_url("avatar", &self.avatar)?;
+        if self.intro.chars().count() > MAX_INTRO_LEN {
+            return Err(format!("intro exceeds {} characters", MAX_INTRO_LEN));
+        }
+        if self.tags.len() > MAX_TAGS {
+            return Err(format!("at most {} tags are allowed", MAX_TAGS));
+        }
+        for tag in &self.tags {
+            if tag.trim().is_empty() || tag.chars().count() > MAX_TAG_LEN {
+                return Err(format!("tags must be 1 to {} characters", MAX_TAG_LEN));
+            }
+        }
+        if let Some(option) = &self.option {
+            if option.len() > MAX_OPTIONS {
+                return Err(format!("at most {} options are allowed", MAX_OPTIONS));
+            }
+            for (key, value) in option {
+                if key.len() > MAX_OPTION_LEN || value.len() > MAX_OPTION_LEN {
+                    return Err(format!("option entries exceed {} bytes", MAX_OPTION_LEN));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct UpgradeResult {
+    pub canister_id: Principal,
+    pub success: bool,
+    pub rejection_code: Option<u32>, // set when the management canister rejected the call
+    #[serde(default)]
+    pub skipped: bool, // held back by the dao's upgrade policy
+    pub message: String,
+    pub finished_at: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct UpgradeBatch {
+    pub id: u64,
+    pub version: String,
+    pub started_at: u64,
+    pub finished_at: Option<u64>, // None while the batch is still running
+    pub results: Vec<UpgradeResult>,
+}
+
+/// Progress of a paid dao creation, so a failed `create_dao` can be retried
+/// without paying again or creating a second canister.
+#[derive(Deserialize, Serialize, Clone, CandidType, PartialEq)]
+pub enum CreationStep {
+    Paid,
+    CanisterAllocated, // the canister exists, the plan's extra cycles are not deposited yet
+    CanisterCreated,
+    CodeInstalled,
+    Registered,
+    Refunded, // the payment was returned, the creation can't be resumed
+}
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct DaoCreation {
+    pub block_height: u64, // payment the creation is keyed by
+    pub memo: u64,
+    pub creator: Principal,
+    pub plan_id: u64,
+    pub info: AddDaoInfo,
+    pub step: CreationStep,
+    pub canister_id: Option<Principal>, // set once the canister exists
+    pub wasm_version: String,
+    pub last_error: Option<String>,
+    pub updated_at: u64,
+    #[serde(default)]
+    pub plan: Option<CreationPlan>, // the plan as it was paid for, None for older creations
 }
 
 #[derive(Deserialize, Serialize, Clone, CandidType)]
@@ -38,4 +236,69 @@ pub enum ControllerAction {
 }
 
 pub type PrincipalText = String;
-pub type Dao = Vec<PrincipalText>;
+pub type Dao = Vec<DaoInfo>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info() -> AddDaoInfo {
+        AddDaoInfo {
+            name: "nnsdao".to_owned(),
+            poster: "https://nnsdao.org/poster.png".to_owned(),
+            avatar: String::new(),
+            tags: vec!["defi".to_owned()],
+            intro: "a dao".to_owned(),
+            option: None,
+        }
+    }
+
+    #[test]
+    fn validate_accepts_valid_info() {
+        assert_eq!(info().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_blank_or_long_name() {
+        let mut blank = info();
+        blank.name = "  ".to_owned();
+        assert!(blank.validate().is_err());
+        let mut long = info();
+        long.name = "a".repeat(MAX_NAME_LEN + 1);
+        assert!(long.validate().is_err());
+        long.name = "a".repeat(MAX_NAME_LEN);
+        assert_eq!(long.validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_rejects_non_https_urls() {
+        let mut info = info();
+        info.avatar = "http://nnsdao.org/avatar.png".to_owned();
+        assert!(info.validate().is_err());
+        info.avatar = "https://".to_owned();
+        assert!(info.validate().is_err());
+        info.avatar = "https://nnsdao.org/a b.png".to_owned();
+        assert!(info.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_too_many_or_empty_tags() {
+        let mut info = info();
+        info.tags = vec!["tag".to_owned(); MAX_TAGS + 1];
+        assert!(info.validate().is_err());
+        info.tags = vec![String::new()];
+        assert!(info.validate().is_err());
+    }
+
+    #[test]
+    fn validate_rejects_long_intro_and_options() {
+        let mut info = info();
+        info.intro = "a".repeat(MAX_INTRO_LEN + 1);
+        assert!(info.validate().is_err());
+        info.intro = String::new();
+        let mut option = HashMap::new();
+        option.insert("key".to_owned(), "a".repeat(MAX_OPTION_LEN + 1));
+        info.option = Some(option);
+        assert!(info.validate().is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use crate::canister_manager::{WASM, WASM_VERSION};
+use crate::error::{ManagerError, ManagerResult};
+use crate::rollout::RolloutStatus;
+use crate::types::CreationStep;
+use crate::Data;
+use candid::{CandidType, Deserialize, Principal};
+use ic_kit::ic;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+#[derive(Deserialize, Serialize, Clone, CandidType)]
+pub struct WasmInfo {
+    pub version: String, // semantic version, MAJOR.MINOR.PATCH
+    pub sha256: Vec<u8>, // hash of the (gzipped) module
+    pub size: u64,       // module size in bytes
+    pub release_notes: String,
+    pub uploaded_by: Principal,
+    pub uploaded_at: u64,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct WasmModule {
+    info: WasmInfo,
+    #[serde(with = "serde_bytes")]
+    wasm: Vec<u8>,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+struct WasmUpload {
+    release_notes: String,
+    #[serde(default)]
+    started_at: u64,
+    #[serde(with = "serde_bytes")]
+    wasm: Vec<u8>,
+}
+
+/// Uploaded dao modules. The module bundled into the manager is always
+/// available as `WASM_VERSION` unless a module with that version is uploaded.
+#[derive(Deserialize, Serialize, Default, Clone)]
+pub struct WasmStore {
+    #[serde(default)]
+    modules: BTreeMap<String, WasmModule>,
+    #[serde(default)]
+    uploads: BTreeMap<String, WasmUpload>,
+    #[serde(default)]
+    default_version: Option<String>,
+}
+
+/// Largest module accepted, install_code can't carry more in one message.
+const MAX_WASM_SIZE: usize = 2 * 1024 * 1024;
+/// Uploads not committed within a day are dropped by `prune_uploads`.
+const UPLOAD_TTL: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+fn validate_version(version: &str) -> ManagerResult<()> {
+    let parts: Vec<&str> = version.split('.').collect();
+    if parts.len() == 3 && parts.iter().all(|part| part.parse::<u64>().is_ok()) {
+        Ok(())
+    } else {
+        Err(ManagerError::InvalidWasm(format!(
+            "{} is not a MAJOR.MINOR.PATCH version",
+            version
+        )))
+    }
+}
+
+pub fn sha256(bytes: &[u8]) -> Vec<u8> {
+    Sha256::digest(bytes).to_vec()
+}
+
+impl WasmStore {
+    /// Start (or restart) a chunked upload of `version`.
+    pub fn upload_begin(&mut self, version: String, release_notes: String) -> ManagerResult<()> {
+        validate_version(&version)?;
+        if self.modules.contains_key(&version) {
+            return Err(ManagerError::InvalidWasm(format!(
+                "version {} already exists",
+                version
+            )));
+        }
+        self.uploads.insert(
+            version,
+            WasmUpload {
+                release_notes,
+                started_at: ic_cdk::api::time(),
+                wasm: vec![],
+            },
+        );
+        Ok(())
+    }
+    /// Append a chunk to a pending upload, returning the bytes received so far.
+    pub fn upload_chunk(&mut self, version: String, chunk: Vec<u8>) -> ManagerResult<u64> {
+        let upload = self
+            .uploads
+            .get_mut(&version)
+            .ok_or(ManagerError::WasmNotFound)?;
+        if upload.wasm.len() + chunk.len() > MAX_WASM_SIZE {
+            return Err(ManagerError::InvalidWasm(format!(
+                "modules are limited to {} bytes",
+                MAX_WASM_SIZE
+            )));
+        }
+        upload.wasm.extend_from_slice(&chunk);
+        Ok(upload.wasm.len() as u64)
+    }
+    /// Finish an upload once its hash matches the one computed by the uploader.
+    pub fn upload_commit(
+        &mut self,
+        version: String,
+        sha256_hash: Vec<u8>,
+        set_default: bool,
+    ) -> ManagerResult<WasmInfo> {
+        let upload = self
+            .uploads
+            .remove(&version)
+            .ok_or(ManagerError::WasmNotFound)?;
+        let hash = sha256(&upload.wasm);
+        if upload.wasm.is_empty() || hash != sha256_hash {
+            return Err(ManagerError::InvalidWasm(
+                "uploaded module does not match the expected sha256".to_owned(),
+            ));
+        }
+
+        let info = WasmInfo {
+            version: version.clone(),
+            sha256: hash,
+            size: upload.wasm.len() as u64,
+            release_notes: upload.release_notes,
+            uploaded_by: ic_cdk::caller(),
+            uploaded_at: ic_cdk::api::time(),
+        };
+        self.modules.insert(
+            version.clone(),
+            WasmModule {
+                info: info.clone(),
+                wasm: upload.wasm,
+            },
+        );
+        if set_default {
+            self.default_version = Some(version);
+        }
+        Ok(info)
+    }
+    /// Drop uploads that were never committed, returning how many were removed.
+    /// `version` drops that upload regardless of its age.
+    pub fn prune_uploads(&mut self, version: Option<String>) -> u64 {
+        let now = ic_cdk::api::time();
+        let before = self.uploads.len();
+        self.uploads
+            .retain(|upload_version, upload| match &version {
+                Some(version) => upload_version != version,
+                None => upload.started_at + UPLOAD_TTL > now,
+            });
+        (before - self.uploads.len()) as u64
+    }
+    /// Remove a module no dao, plan, creation or rollout still needs.
+    pub fn delete(&mut self, version: String) -> ManagerResult<WasmInfo> {
+        if !self.modules.contains_key(&version) {
+            return Err(ManagerError::WasmNotFound);
+        }
+        if self.default_version() == version {
+            return Err(ManagerError::InvalidWasm(
+                "the default version can't be deleted".to_owned(),
+            ));
+        }
+        if let Some(user) = version_user(&version) {
+            return Err(ManagerError::InvalidWasm(format!(
+                "version {} is still used by {}",
+                version, user
+            )));
+        }
+        self.modules
+            .remove(&version)
+            .map(|module| module.info)
+            .ok_or(ManagerError::WasmNotFound)
+    }
+    pub fn set_default(&mut self, version: String) -> ManagerResult<()> {
+        self.wasm_info(&version)?;
+        self.default_version = Some(version);
+        Ok(())
+    }
+    /// Version installed into newly created daos.
+    pub fn default_version(&self) -> String {
+        self.default_version
+            .clone()
+            .unwrap_or_else(|| WASM_VERSION.to_owned())
+    }
+    pub fn wasm_list(&self) -> Vec<WasmInfo> {
+        let mut list: Vec<WasmInfo> = self
+            .modules
+            .values()
+            .map(|module| module.info.clone())
+            .collect();
+        if !self.modules.contains_key(WASM_VERSION) {
+            list.insert(0, builtin_info());
+        }
+        list
+    }
+    pub fn wasm_info(&self, version: &str) -> ManagerResult<WasmInfo> {
+        match self.modules.get(version) {
+            Some(module) => Ok(module.info.clone()),
+            None if version == WASM_VERSION => Ok(builtin_info()),
+            None => Err(ManagerError::WasmNotFound),
+        }
+    }
+    pub fn module(&self, version: &str) -> ManagerResult<&[u8]> {
+        match self.modules.get(version) {
+            Some(module) => Ok(&module.wasm),
+            None if version == WASM_VERSION => Ok(WASM),
+            None => Err(ManagerError::WasmNotFound),
+        }
+    }
+}
+
+/// Something that still installs or runs `version`, if any.
+fn version_user(version: &str) -> Option<String> {
+    let data = ic::get::<Data>();
+    let version = Some(version.to_owned());
+    if let Some(dao) = data
+        .dao_admin
+        .daos
+        .values()
+        .find(|dao| dao.wasm_version == version)
+    {
+        return Some(format!("dao {}", dao.canister_id));
+    }
+    // still needed to roll back to or to approve a held back upgrade
+    if let Some(dao) = data.dao_admin.daos.values().find(|dao| {
+        dao.version_history
+            .iter()
+            .any(|previous| Some(previous) == version.as_ref())
+            || dao.pending_upgrade.as_ref().map(|pending| &pending.version) == version.as_ref()
+    }) {
+        return Some(format!("the history of dao {}", dao.canister_id));
+    }
+    if let Some(plan) = data
+        .plans
+        .plans
+        .values()
+        .find(|plan| plan.wasm_version == version)
+    {
+        return Some(format!("plan {}", plan.id));
+    }
+    if let Some(creation) = data.dao_admin.creations.values().find(|creation| {
+        Some(&creation.wasm_version) == version.as_ref()
+            && !matches!(
+                creation.step,
+                CreationStep::Registered | CreationStep::Refunded
+            )
+    }) {
+        return Some(format!("creation {}", creation.block_height));
+    }
+    data.rollout
+        .rollouts
+        .iter()
+        .find(|rollout| {
+            Some(&rollout.version) == version.as_ref()
+                && matches!(
+                    rollout.status,
+                    RolloutStatus::Running | RolloutStatus::Paused(_)
+                )
+        })
+        .map(|rollout| format!("rollout {}", rollout.id))
+}
+
+thread_local! {
+    // hashing the bundled module on every lookup is wasteful, it never changes
+    static BUILTIN_SHA256: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+fn builtin_info() -> WasmInfo {
+    let hash = BUILTIN_SHA256.with(|cached| {
+        cached
+            .borrow_mut()
+            .get_or_insert_with(|| sha256(WASM))
+            .clone()
+    });
+    WasmInfo {
+        version: WASM_VERSION.to_owned(),
+        sha256: hash,
+        size: WASM.len() as u64,
+        release_notes: "Bundled with the manager".to_owned(),
+        uploaded_by: ic_cdk::id(),
+        uploaded_at: 0,
+    }
+}